@@ -77,6 +77,10 @@ const PROTO_FILES: &[&str] = &[
 ];
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // embedded by sqlx::migrate!
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=proto");
+
     tonic_prost_build::configure()
         .build_server(false)
         .build_client(true)
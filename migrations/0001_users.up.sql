@@ -0,0 +1,42 @@
+-- Written to be a no-op on databases created before migrations existed,
+-- so those are adopted by simply running it
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+CREATE TABLE IF NOT EXISTS users (
+    id                     UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    email                  VARCHAR(255) UNIQUE NOT NULL,
+
+    tags                   TEXT[],
+    inbounds               TEXT[],
+
+    traffic_limit          BIGINT NOT NULL DEFAULT 0,
+    traffic_used           BIGINT NOT NULL DEFAULT 0,
+
+    reset_traffic_every    BIGINT,
+    last_traffic_reset_at  TIMESTAMPTZ,
+
+    expire_at              TIMESTAMPTZ,
+
+    ip_limit               BIGINT NOT NULL DEFAULT 0,
+    ip_list                JSONB,
+    ip_limit_punishment    JSONB,
+    ip_expire_after        BIGINT NOT NULL DEFAULT 0,
+
+    is_active              BOOLEAN NOT NULL DEFAULT true,
+    created_at             TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at             TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE OR REPLACE FUNCTION set_updated_at()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at := NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS set_timestamp ON users;
+CREATE TRIGGER set_timestamp
+BEFORE UPDATE ON users
+FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
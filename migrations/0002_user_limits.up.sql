@@ -0,0 +1,4 @@
+-- Set while the daemon keeps a user out of Xray
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled_reason TEXT;
+-- End of an ip_limit suspension
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_until TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- Last counter values seen per user, so reading them again
+-- after a crash only adds what grew since
+CREATE TABLE IF NOT EXISTS traffic_checkpoints (
+    user_id     UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    epoch       UUID NOT NULL,
+    uplink      BIGINT NOT NULL,
+    downlink    BIGINT NOT NULL,
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS ip_bans (
+    id            UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id       UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip            TEXT NOT NULL,
+    banned_until  TIMESTAMPTZ NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
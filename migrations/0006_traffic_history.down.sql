@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS traffic_rollups;
+DROP TABLE IF EXISTS traffic_samples;
+DROP TABLE IF EXISTS stat_checkpoints;
@@ -0,0 +1,35 @@
+-- traffic_checkpoints for inbound and outbound counters
+CREATE TABLE IF NOT EXISTS stat_checkpoints (
+    kind        TEXT NOT NULL,
+    name        TEXT NOT NULL,
+    epoch       UUID NOT NULL,
+    uplink      BIGINT NOT NULL,
+    downlink    BIGINT NOT NULL,
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (kind, name)
+);
+
+-- What each collection moved, kept for `accounting.sample_retention`
+CREATE TABLE IF NOT EXISTS traffic_samples (
+    id          BIGSERIAL PRIMARY KEY,
+    kind        TEXT NOT NULL,
+    name        TEXT NOT NULL,
+    sampled_at  TIMESTAMPTZ NOT NULL,
+    uplink      BIGINT NOT NULL,
+    downlink    BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS traffic_samples_sampled_at
+ON traffic_samples (sampled_at);
+
+-- Samples summed per UTC hour, day and month, kept forever.
+-- Keyed by name rather than user id so billing outlives deleted users
+CREATE TABLE IF NOT EXISTS traffic_rollups (
+    kind          TEXT NOT NULL,
+    name          TEXT NOT NULL,
+    bucket        TEXT NOT NULL,
+    bucket_start  TIMESTAMPTZ NOT NULL,
+    uplink        BIGINT NOT NULL,
+    downlink      BIGINT NOT NULL,
+    PRIMARY KEY (kind, name, bucket, bucket_start)
+);
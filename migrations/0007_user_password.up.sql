@@ -0,0 +1,3 @@
+-- Trojan account secret, generated for new and existing users
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password TEXT NOT NULL
+    DEFAULT encode(gen_random_bytes(16), 'hex');
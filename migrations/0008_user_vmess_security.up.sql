@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS vmess_security TEXT NOT NULL DEFAULT 'auto';
@@ -0,0 +1,3 @@
+-- Shadowsocks 2022 secret, cut to each inbound method's key size
+ALTER TABLE users ADD COLUMN IF NOT EXISTS ss_key TEXT NOT NULL
+    DEFAULT encode(gen_random_bytes(32), 'base64');
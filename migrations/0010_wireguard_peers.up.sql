@@ -0,0 +1,12 @@
+-- One peer per user and WireGuard inbound, the row holds the tunnel
+-- address, so deleting the user releases it
+CREATE TABLE IF NOT EXISTS wireguard_peers (
+    user_id      UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    inbound_tag  TEXT NOT NULL,
+    address      TEXT NOT NULL,
+    private_key  TEXT NOT NULL,
+    public_key   TEXT NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, inbound_tag),
+    UNIQUE (inbound_tag, address)
+);
@@ -0,0 +1,93 @@
+use lazy_static::lazy_static;
+use sqlx::PgPool;
+use tokio::time::{Duration, MissedTickBehavior};
+use crate::data::postgres::types::StatKind;
+use crate::data::postgres::TrafficResetTarget;
+use crate::Client;
+use super::supervisor;
+
+lazy_static!(
+    /// Two collections at once would both add the growth since the same checkpoint
+    static ref COLLECTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+);
+
+/// Moves the traffic counted by the running Xray into `users.traffic_used`
+/// and the traffic history, returns how many users had any.
+///
+/// Counters are read without resetting them and compared to checkpoints
+/// stored with `traffic_used`, so a crash anywhere in between at worst
+/// repeats the work on the next run instead of losing or doubling bytes
+pub async fn collect(pool: &PgPool) -> anyhow::Result<usize> {
+    let _guard = COLLECTING.lock().await;
+
+    let Some(epoch) = supervisor::epoch() else {
+        if super::is_xray_running() {
+            anyhow::bail!("Xray was not started by this daemon and has no saved epoch, \
+                its traffic is not counted until it is restarted");
+        }
+        return Ok(0);
+    };
+
+    let client = Client::connect().await?;
+    let counters = client.all_users_traffic(false).await?;
+    let inbounds = client.all_traffic(StatKind::Inbound.as_str(), false).await?;
+    let outbounds = client.all_traffic(StatKind::Outbound.as_str(), false).await?;
+
+    // restarted while reading, the next run gets the new process
+    if supervisor::epoch() != Some(epoch) {
+        return Ok(0);
+    }
+
+    crate::data::postgres::record_link_traffic(pool, epoch, StatKind::Inbound, &inbounds).await?;
+    crate::data::postgres::record_link_traffic(pool, epoch, StatKind::Outbound, &outbounds).await?;
+
+    Ok(crate::data::postgres::record_traffic(pool, epoch, &counters).await?)
+}
+
+/// Zeroes `traffic_used` of the target users and re-enables the ones that
+/// were only disabled for their quota. Used by the schedule and by
+/// `users reset-traffic`
+pub async fn reset_traffic(
+    pool: &PgPool,
+    target: TrafficResetTarget,
+) -> anyhow::Result<Vec<String>> {
+    let emails = crate::data::postgres::reset_traffic(pool, &target).await?;
+
+    if matches!(target, TrafficResetTarget::Due) {
+        for email in &emails {
+            println!("[necko-xray]: Traffic of {} reset", email);
+        }
+    }
+
+    super::enforce::reconcile(pool).await?;
+
+    Ok(emails)
+}
+
+/// Runs `collect` every `accounting.interval` seconds, then resets the
+/// users whose period is over and applies the limits to the new totals.
+/// Samples past `accounting.sample_retention` are dropped on the way
+pub async fn run(pool: PgPool) {
+    let settings = &crate::settings::get().accounting;
+    let interval = Duration::from_secs(settings.interval.max(1));
+    let retention = settings.sample_retention as i64;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        if super::is_xray_running()
+            && let Err(e) = collect(&pool).await {
+            eprintln!("[necko-xray]: Failed to collect traffic: {:#}", e);
+        }
+
+        if let Err(e) = reset_traffic(&pool, TrafficResetTarget::Due).await {
+            eprintln!("[necko-xray]: Failed to apply user limits: {:#}", e);
+        }
+
+        if let Err(e) = crate::data::postgres::prune_traffic_samples(&pool, retention).await {
+            eprintln!("[necko-xray]: Failed to prune traffic samples: {}", e);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::config::LiveInbound;
+use crate::data::postgres::types::{User, VmessSecurity};
+use crate::proto::common::protocol::SecurityType;
+use crate::Client;
+use super::rebuild::REBUILT_PROTOCOLS;
+
+/// Protocols whose inbounds take users from the database
+pub const MANAGED_PROTOCOLS: &[&str] = &["vless", "vmess", "trojan", "shadowsocks", "socks", "http", "wireguard"];
+
+/// Adds the user to one inbound with the account type its protocol needs.
+/// `inbounds` comes from `config::live_inbounds`
+pub async fn add(
+    client: &Client,
+    inbounds: &HashMap<String, LiveInbound>,
+    user: &User,
+    tag: &str,
+) -> anyhow::Result<()> {
+    let Some(inbound) = inbounds.get(tag) else {
+        anyhow::bail!("Inbound {} is not in the active config", tag);
+    };
+
+    match inbound.protocol.as_str() {
+        "vless" => client.add_vless_user(tag, &user.id.to_string(), &user.email).await,
+        "vmess" => client.add_vmess_user(
+            tag, &user.id.to_string(), vmess_security_type(user.vmess_security), &user.email).await,
+        "trojan" => client.add_trojan_user(tag, &user.password, &user.email).await,
+        "shadowsocks" => {
+            let key = shadowsocks_key(user, inbound)
+                .map_err(|e| anyhow::anyhow!("Inbound {}: {}", tag, e))?;
+            client.add_shadowsocks_2022_user(tag, &key, &user.email).await
+        }
+        // users go in with the whole inbound, see `rebuild::refresh`
+        protocol if REBUILT_PROTOCOLS.contains(&protocol) => Ok(()),
+        protocol => anyhow::bail!("Inbound {} is {}, users can only be added to {} inbounds",
+                                  tag, protocol, MANAGED_PROTOCOLS.join(", ")),
+    }
+}
+
+/// Takes the user out of one inbound, rebuilt inbounds lose them with `rebuild::refresh`
+pub async fn remove(
+    client: &Client,
+    inbounds: &HashMap<String, LiveInbound>,
+    email: &str,
+    tag: &str,
+) -> anyhow::Result<()> {
+    if inbounds.get(tag).is_some_and(|i| REBUILT_PROTOCOLS.contains(&i.protocol.as_str())) {
+        return Ok(());
+    }
+
+    client.remove_user(tag, email).await
+}
+
+/// Adds the user to every inbound in `users.inbounds`
+pub async fn add_all(client: &Client, user: &User) -> anyhow::Result<()> {
+    let inbounds = crate::config::live_inbounds();
+
+    for tag in user.inbounds.as_deref().unwrap_or_default() {
+        add(client, &inbounds, user, tag).await?;
+    }
+
+    Ok(())
+}
+
+/// Applies an update of an enabled user: leaves dropped inbounds, joins
+/// new ones and re-adds the user where its account for the protocol changed
+pub async fn sync(
+    client: &Client,
+    old: &User,
+    new: &User,
+) -> anyhow::Result<()> {
+    let inbounds = crate::config::live_inbounds();
+    let old_tags: HashSet<_> = old.inbounds.as_deref().unwrap_or_default().iter().collect();
+    let new_tags: HashSet<_> = new.inbounds.as_deref().unwrap_or_default().iter().collect();
+
+    for tag in old_tags.difference(&new_tags) {
+        let _ = remove(client, &inbounds, &old.email, tag).await;
+    }
+
+    for tag in new_tags.difference(&old_tags) {
+        add(client, &inbounds, new, tag).await?;
+    }
+
+    for tag in old_tags.intersection(&new_tags) {
+        let protocol = inbounds.get(*tag).map(|i| i.protocol.as_str()).unwrap_or_default();
+        if account_changed(protocol, old, new) {
+            let _ = client.remove_user(tag, &old.email).await;
+            add(client, &inbounds, new, tag).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether Xray holds an outdated account of the user on a `protocol` inbound
+fn account_changed(protocol: &str, old: &User, new: &User) -> bool {
+    match protocol {
+        "vmess" => old.vmess_security != new.vmess_security,
+        "trojan" => old.password != new.password,
+        "shadowsocks" => old.ss_key != new.ss_key,
+        _ => false,
+    }
+}
+
+fn vmess_security_type(security: VmessSecurity) -> SecurityType {
+    match security {
+        VmessSecurity::Auto => SecurityType::Auto,
+        VmessSecurity::Aes128Gcm => SecurityType::Aes128Gcm,
+        VmessSecurity::Chacha20Poly1305 => SecurityType::Chacha20Poly1305,
+        VmessSecurity::None => SecurityType::None,
+        VmessSecurity::Zero => SecurityType::Zero,
+    }
+}
+
+/// The user's key on a Shadowsocks inbound, as clients put it after the
+/// server key in their password (`<server key>:<user key>`)
+pub fn shadowsocks_key(user: &User, inbound: &LiveInbound) -> anyhow::Result<String> {
+    let method = inbound.settings["method"].as_str().unwrap_or_default();
+    ss2022_user_key(&user.ss_key, method)
+}
+
+/// Keys of the user on each of their Shadowsocks 2022 inbounds, by tag
+pub fn shadowsocks_keys(user: &User) -> BTreeMap<String, String> {
+    let inbounds = crate::config::live_inbounds();
+
+    user.inbounds
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|tag| {
+            let inbound = inbounds.get(tag).filter(|i| i.protocol == "shadowsocks")?;
+            Some((tag.clone(), shadowsocks_key(user, inbound).ok()?))
+        })
+        .collect()
+}
+
+/// Cuts the user's 32 byte secret down to the key size of `method`.
+/// Only the 2022 AES methods have per-user keys
+fn ss2022_user_key(secret: &str, method: &str) -> anyhow::Result<String> {
+    let size = match method {
+        "2022-blake3-aes-128-gcm" => 16,
+        "2022-blake3-aes-256-gcm" => 32,
+        _ => anyhow::bail!("method `{}` has no per-user keys, use a 2022-blake3-aes method", method),
+    };
+
+    let secret = BASE64.decode(secret)?;
+    if secret.len() < size {
+        anyhow::bail!("stored Shadowsocks secret is too short");
+    }
+
+    Ok(BASE64.encode(&secret[..size]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ss2022_user_key_test() {
+        let secret = BASE64.encode([7u8; 32]);
+
+        let key = ss2022_user_key(&secret, "2022-blake3-aes-128-gcm").unwrap();
+        assert_eq!(BASE64.decode(key).unwrap(), [7u8; 16]);
+        assert_eq!(ss2022_user_key(&secret, "2022-blake3-aes-256-gcm").unwrap(), secret);
+        assert!(ss2022_user_key(&secret, "2022-blake3-chacha20-poly1305").is_err());
+        assert!(ss2022_user_key(&secret, "aes-256-gcm").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::os::unix::fs::PermissionsExt;
+use nix::unistd::{chown, getuid, Gid, Group};
+use tokio::net::unix::UCred;
+use crate::api::Request;
+use crate::settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    ReadOnly,
+    Admin,
+}
+
+impl Access {
+    pub fn required(request: &Request) -> Self {
+        match request {
+            Request::XrayStatus
+            | Request::Logs(_)
+            | Request::GetStatsUserOnlineCount { .. }
+            | Request::GetStatsUserOnlineIpList { .. }
+            | Request::GetStatsUserTraffic { .. }
+            | Request::GetStatsInboundTraffic { .. }
+            | Request::GetStatsOutboundTraffic { .. }
+            | Request::GetStatsSystem
+            | Request::GetAllUsers
+            | Request::GetUserSessions { .. }
+            | Request::GetTrafficHistory { .. } => Access::ReadOnly,
+
+            Request::StartXray
+            | Request::StopXray
+            | Request::RestartXray
+            | Request::ReloadProfile { .. }
+            | Request::CreateUser { .. }
+            | Request::UpdateUser { .. }
+            | Request::DeleteUser { .. }
+            | Request::RollbackConfig
+            | Request::ResetTraffic { .. }
+            | Request::ExtendUser { .. }
+            | Request::RotateShadowsocksKey { .. }
+            // full records carry the users' UUIDs, passwords and keys
+            | Request::GetUser { .. }
+            | Request::ListUsers(_)
+            // holds the peer's private key
+            | Request::GetWireguardConfig { .. } => Access::Admin,
+        }
+    }
+
+    /// What the peer may do, `None` when it is not allowed on the socket at all.
+    /// SO_PEERCRED only carries the primary group of the client
+    pub fn granted(cred: &UCred) -> Option<Self> {
+        let settings = &settings::get().daemon;
+        let (uid, gid) = (cred.uid(), cred.gid());
+
+        if uid == 0 || uid == getuid().as_raw()
+            || settings.admin_uids.contains(&uid)
+            || settings.admin_gids.contains(&gid) {
+            Some(Access::Admin)
+        } else if settings.readonly_uids.contains(&uid)
+            || settings.readonly_gids.contains(&gid) {
+            Some(Access::ReadOnly)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies the configured mode and group to a freshly bound socket
+pub fn secure_socket(path: &str) -> anyhow::Result<()> {
+    let settings = &settings::get().daemon;
+
+    if let Some(group) = &settings.socket_group {
+        let gid = match group.parse::<u32>() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => Group::from_name(group)?
+                .ok_or_else(|| anyhow::anyhow!("Unknown socket group `{}`", group))?
+                .gid,
+        };
+        chown(path, None, Some(gid))
+            .map_err(|e| anyhow::anyhow!("Failed to set socket group `{}`: {}", group, e))?;
+    }
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(settings.socket_mode))
+        .map_err(|e| anyhow::anyhow!("Failed to set socket mode {:o}: {}", settings.socket_mode, e))?;
+
+    Ok(())
+}
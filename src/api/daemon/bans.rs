@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+use prost::Message;
+use sqlx::PgPool;
+use crate::config::BLOCK_OUTBOUND;
+use crate::data::postgres::types::{ban_rule_tag, IpBan};
+use crate::proto::app::router::command::{AddRuleRequest, RemoveRuleRequest};
+use crate::proto::app::router::routing_rule::TargetTag;
+use crate::proto::app::router::{Cidr, Config as RouterConfig, GeoIp, RoutingRule};
+use crate::proto::common::serial::TypedMessage;
+use crate::proto::core::Config as CoreConfig;
+use crate::Client;
+
+pub const ROUTER_CONFIG_TYPE: &str = "xray.app.router.Config";
+
+/// Router config of `config` with a rule per ban in front of the profile's
+/// rules, which could otherwise match banned traffic first
+pub fn router_with_bans(config: &CoreConfig, bans: &[IpBan]) -> anyhow::Result<TypedMessage> {
+    let router = config.app
+        .iter()
+        .find(|m| m.r#type == ROUTER_CONFIG_TYPE)
+        .ok_or_else(|| anyhow::anyhow!("Routing missing in converted config"))?;
+
+    if bans.is_empty() {
+        return Ok(router.clone());
+    }
+
+    // rules pointing at a missing outbound would make Xray reject the router
+    if !config.outbound.iter().any(|o| o.tag == BLOCK_OUTBOUND) {
+        eprintln!("[necko-xray]: No {} outbound in the config, {} IP bans are not applied",
+                  BLOCK_OUTBOUND, bans.len());
+        return Ok(router.clone());
+    }
+
+    let mut decoded = RouterConfig::decode(router.value.as_slice())?;
+    let mut rules = bans.iter().map(ban_rule).collect::<anyhow::Result<Vec<_>>>()?;
+    rules.append(&mut decoded.rule);
+    decoded.rule = rules;
+
+    Ok(TypedMessage {
+        r#type: ROUTER_CONFIG_TYPE.to_string(),
+        value: decoded.encode_to_vec(),
+    })
+}
+
+fn ban_rule(ban: &IpBan) -> anyhow::Result<RoutingRule> {
+    let ip: IpAddr = ban.ip.parse()
+        .map_err(|e| anyhow::anyhow!("Invalid banned IP `{}`: {}", ban.ip, e))?;
+    let (ip, prefix) = match ip {
+        IpAddr::V4(v4) => (v4.octets().to_vec(), 32),
+        IpAddr::V6(v6) => (v6.octets().to_vec(), 128),
+    };
+
+    Ok(RoutingRule {
+        rule_tag: ban.rule_tag(),
+        source_geoip: vec![GeoIp { cidr: vec![Cidr { ip, prefix }], ..Default::default() }],
+        user_email: vec![ban.email.clone()],
+        target_tag: Some(TargetTag::Tag(BLOCK_OUTBOUND.to_string())),
+        ..Default::default()
+    })
+}
+
+/// Replaces the routing rules of the running Xray with the config's rules
+/// plus all active bans
+pub async fn apply(pool: &PgPool, client: &Client) -> anyhow::Result<()> {
+    let bans = crate::data::postgres::get_active_ip_bans(pool).await?;
+    let config = crate::config::current_config_protobuf().await?;
+
+    client.routing()
+        .add_rule(AddRuleRequest {
+            config: Some(router_with_bans(&config, &bans)?),
+            should_append: false,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Puts the bans back into a freshly started Xray, its rules come from the
+/// config file only
+pub async fn restore(pool: &PgPool, client: &Client) -> anyhow::Result<()> {
+    if crate::data::postgres::get_active_ip_bans(pool).await?.is_empty() {
+        return Ok(());
+    }
+
+    apply(pool, client).await
+}
+
+/// Lifts the bans that are over
+pub async fn expire(pool: &PgPool, client: &Client) -> anyhow::Result<()> {
+    for id in crate::data::postgres::take_expired_ip_bans(pool).await? {
+        // gone already if Xray restarted since
+        let _ = client.routing()
+            .remove_rule(RemoveRuleRequest { rule_tag: ban_rule_tag(id) })
+            .await;
+        println!("[necko-xray]: Ban {} lifted", ban_rule_tag(id));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use sqlx::PgPool;
+use tokio::sync::Notify;
+use crate::data::postgres::types::{DisabledReason, User};
+use crate::Client;
+
+/// Longest sleep of the expiry timer, in case the clock jumps
+const EXPIRY_RECHECK: Duration = Duration::from_secs(60 * 60);
+
+lazy_static!(
+    /// Runs from the accounting tick and from user updates, never both at once
+    static ref RECONCILING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+
+    static ref EXPIRY_CHANGED: Notify = Notify::new();
+);
+
+/// Wakes the expiry timer up after an `expire_at` or `suspended_until`
+/// was set or changed
+pub fn expiry_changed() {
+    EXPIRY_CHANGED.notify_one();
+}
+
+/// Sleeps until the next user expires or a suspension ends and reconciles
+/// right then, instead of up to an accounting interval later
+pub async fn run_expiry_timer(pool: PgPool) {
+    loop {
+        let next = match crate::data::postgres::get_next_deadline(&pool).await {
+            Ok(next) => next,
+            Err(e) => {
+                eprintln!("[necko-xray]: Failed to get the next deadline: {}", e);
+                None
+            }
+        };
+
+        let sleep = next
+            .and_then(|at| (at - Utc::now()).to_std().ok())
+            .unwrap_or(EXPIRY_RECHECK)
+            .min(EXPIRY_RECHECK);
+
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            _ = EXPIRY_CHANGED.notified() => {}
+        }
+
+        if let Err(e) = reconcile(&pool).await {
+            eprintln!("[necko-xray]: Failed to apply user limits: {:#}", e);
+        }
+    }
+}
+
+/// Brings `disabled_reason` in line with each user's limits and removes
+/// users from, or puts them back into, their inbounds accordingly.
+/// Users whose Xray change failed keep their old reason and are retried
+/// on the next call
+pub async fn reconcile(pool: &PgPool) -> anyhow::Result<()> {
+    let _guard = RECONCILING.lock().await;
+
+    let users = crate::data::postgres::get_users_to_reconcile(pool).await?;
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    // a stopped Xray gets the right users from `sync_users` on start
+    let client = if super::is_xray_running() {
+        Some(Client::connect().await?)
+    } else {
+        None
+    };
+
+    for (user, wanted) in users {
+        let was_enabled = user.is_active && user.disabled_reason.is_none();
+        let enabled = user.is_active && wanted.is_none();
+
+        if let Some(client) = &client {
+            let applied = match (was_enabled, enabled) {
+                (true, false) => disable(client, &user).await,
+                (false, true) => enable(client, &user).await,
+                _ => Ok(()),
+            };
+
+            if let Err(e) = applied {
+                eprintln!("[necko-xray]: Failed to update user {} in Xray: {}", user.email, e);
+                continue;
+            }
+        }
+
+        crate::data::postgres::set_disabled_reason(pool, user.id, wanted).await?;
+
+        match (was_enabled, enabled) {
+            (true, false) => println!("[necko-xray]: Disabled user {} ({})",
+                                      user.email, describe(wanted)),
+            (false, true) => println!("[necko-xray]: Enabled user {} again", user.email),
+            _ => {}
+        }
+    }
+
+    // inbounds taking enabled users from their settings are rebuilt as a whole
+    super::rebuild::refresh(pool).await
+}
+
+pub async fn disable(client: &Client, user: &User) -> anyhow::Result<()> {
+    for tag in user.inbounds.as_deref().unwrap_or_default() {
+        // not being in the inbound is what we want anyway
+        let _ = client.remove_user(tag, &user.email).await;
+    }
+
+    Ok(())
+}
+
+pub async fn enable(client: &Client, user: &User) -> anyhow::Result<()> {
+    let inbounds = crate::config::live_inbounds();
+
+    for tag in user.inbounds.as_deref().unwrap_or_default() {
+        // a half applied earlier attempt may have left the user there
+        let _ = client.remove_user(tag, &user.email).await;
+        super::accounts::add(client, &inbounds, user, tag).await?;
+    }
+
+    Ok(())
+}
+
+fn describe(reason: Option<DisabledReason>) -> &'static str {
+    match reason {
+        Some(DisabledReason::TrafficLimit) => "traffic limit reached",
+        Some(DisabledReason::Expired) => "expired",
+        Some(DisabledReason::IpLimit) => "suspended for using too many IPs",
+        None => "enabled",
+    }
+}
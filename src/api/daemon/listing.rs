@@ -0,0 +1,96 @@
+use sqlx::PgPool;
+use tokio::net::UnixStream;
+use uuid::Uuid;
+use crate::api::{ApiError, Response};
+use crate::data::postgres::types::{UserListFilter, UserSort};
+use super::protocol::write_frame;
+
+/// Rows read per query, keeps the daemon's memory flat on big tables
+const CHUNK_SIZE: i64 = 500;
+
+/// Sends every user matching `filter` as its own `Response::User` frame and
+/// finishes with `Response::UserListEnd`, which carries the cursor of the
+/// next page when `filter.limit` cut the list short
+pub(super) async fn stream(
+    stream: &mut UnixStream,
+    pool: &PgPool,
+    filter: UserListFilter,
+) -> anyhow::Result<()> {
+    let after = filter.after.as_deref().map(|c| parse_cursor(c, filter.sort)).transpose();
+    let mut after = match after {
+        Ok(after) => after,
+        Err(e) => return write_frame(stream, &Response::from_error(&e.into())).await,
+    };
+    let mut left = filter.limit.map(i64::from);
+
+    loop {
+        // one row more than the page needs tells whether another page follows
+        let chunk = left.map_or(CHUNK_SIZE, |left| (left + 1).min(CHUNK_SIZE));
+        let rows = match crate::data::postgres::list_users(pool, &filter, after, chunk).await {
+            Ok(rows) => rows,
+            Err(e) => return write_frame(stream, &Response::from_error(&e.into())).await,
+        };
+        let done = (rows.len() as i64) < chunk;
+
+        for (user, key) in rows {
+            if left == Some(0) {
+                let next = after.map(|(key, id)| cursor(filter.sort, key, id));
+                return write_frame(stream, &Response::UserListEnd { next }).await;
+            }
+
+            after = Some((key, user.id));
+            write_frame(stream, &Response::User(Box::new(user))).await?;
+            if let Some(left) = &mut left {
+                *left -= 1;
+            }
+        }
+
+        if done {
+            return write_frame(stream, &Response::UserListEnd { next: None }).await;
+        }
+    }
+}
+
+fn sort_name(sort: UserSort) -> &'static str {
+    match sort {
+        UserSort::Created => "created",
+        UserSort::Usage => "usage",
+        UserSort::Expiry => "expiry",
+    }
+}
+
+/// `<sort>:<sort key>:<id>`, opaque to clients
+fn cursor(sort: UserSort, key: i64, id: Uuid) -> String {
+    format!("{}:{}:{}", sort_name(sort), key, id)
+}
+
+fn parse_cursor(cursor: &str, sort: UserSort) -> Result<(i64, Uuid), ApiError> {
+    let invalid = || ApiError::bad_request(format!(
+        "Invalid cursor `{}`, pass --after exactly as printed with the same --sort", cursor));
+
+    let mut parts = cursor.splitn(3, ':');
+    let (Some(name), Some(key), Some(id)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if name != sort_name(sort) {
+        return Err(invalid());
+    }
+
+    Ok((key.parse().map_err(|_| invalid())?, id.parse().map_err(|_| invalid())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_test() {
+        let id = Uuid::new_v4();
+        let next = cursor(UserSort::Usage, -1024, id);
+
+        assert_eq!(parse_cursor(&next, UserSort::Usage).unwrap(), (-1024, id));
+        assert!(parse_cursor(&next, UserSort::Created).is_err());
+        assert!(parse_cursor("usage:1", UserSort::Usage).is_err());
+        assert!(parse_cursor("usage:x:y", UserSort::Usage).is_err());
+    }
+}
@@ -2,48 +2,48 @@ use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::Path;
 
-const PID_FILE: &str = "/tmp/necko-xray.pid";
+fn pid_file() -> &'static str {
+    &crate::settings::get().daemon.pid_file
+}
 
 pub fn acquire_lock() -> anyhow::Result<()> {
-    if Path::new(PID_FILE).exists() {
-        let mut file = File::open(PID_FILE)?;
+    if Path::new(pid_file()).exists() {
+        let mut file = File::open(pid_file())?;
         let mut pid_str = String::new();
         file.read_to_string(&mut pid_str)?;
 
-        if let Ok(pid) = pid_str.trim().parse::<i32>() {
-            if is_process_running(pid) {
-                anyhow::bail!("Daemon already running with PID: {}", pid);
-            }
+        if let Ok(pid) = pid_str.trim().parse::<i32>()
+            && is_process_running(pid) {
+            anyhow::bail!("Daemon already running with PID: {}", pid);
         }
 
-        fs::remove_file(PID_FILE)?;
+        fs::remove_file(pid_file())?;
     }
 
     let pid = std::process::id();
-    let mut file = File::create(PID_FILE)?;
+    let mut file = File::create(pid_file())?;
     file.write_all(pid.to_string().as_bytes())?;
 
     Ok(())
 }
 
 pub fn release_lock() {
-    let _ = fs::remove_file(PID_FILE);
+    let _ = fs::remove_file(pid_file());
 }
 
 // pub fn get_daemon_pid() -> Option<i32> {
-//     let mut file = File::open(PID_FILE).ok()?;
+//     let mut file = File::open(pid_file()).ok()?;
 //     let mut pid_str = String::new();
 //     file.read_to_string(&mut pid_str).ok()?;
 //     pid_str.trim().parse().ok()
 // }
 
 pub fn is_daemon_running() -> bool {
-    if let Ok(mut file) = File::open(PID_FILE) {
+    if let Ok(mut file) = File::open(pid_file()) {
         let mut pid_str = String::new();
-        if file.read_to_string(&mut pid_str).is_ok() {
-            if let Ok(pid) = pid_str.trim().parse::<i32>() {
-                return is_process_running(pid);
-            }
+        if file.read_to_string(&mut pid_str).is_ok()
+            && let Ok(pid) = pid_str.trim().parse::<i32>() {
+            return is_process_running(pid);
         }
     }
     false
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, SyncSender};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, watch};
+use crate::api::Response;
+use super::protocol::write_frame;
+
+/// Lines kept in memory for `necko-xray logs`
+const BUFFER_LINES: usize = 5000;
+const FOLLOW_CHANNEL_SIZE: usize = 1024;
+/// Lines waiting for the log file, more are left out of the file
+const FILE_CHANNEL_SIZE: usize = 4096;
+const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated files kept next to the log file (file.1 ... file.N)
+const LOG_FILE_KEEP: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+    clap::ValueEnum)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    /// Xray prints its level in brackets, e.g. `2025/10/18 12:00:00 [Warning] ...`.
+    /// Lines without one (access log) count as info
+    fn parse(line: &str) -> Self {
+        if line.contains("[Debug]") {
+            LogLevel::Debug
+        } else if line.contains("[Warning]") {
+            LogLevel::Warning
+        } else if line.contains("[Error]") {
+            LogLevel::Error
+        } else {
+            LogLevel::Info
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogLine {
+    pub at: DateTime<Utc>,
+    pub level: LogLevel,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LogFilter {
+    pub follow: bool,
+    pub level: Option<LogLevel>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl LogFilter {
+    fn matches(&self, line: &LogLine) -> bool {
+        self.level.is_none_or(|l| line.level >= l)
+            && self.since.is_none_or(|s| line.at >= s)
+    }
+}
+
+lazy_static!(
+    static ref BUFFER: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::with_capacity(BUFFER_LINES));
+
+    static ref FOLLOWERS: broadcast::Sender<LogLine> =
+        broadcast::channel(FOLLOW_CHANNEL_SIZE).0;
+
+    /// `None` without `xray.log_file`
+    static ref FILE_LINES: Option<SyncSender<String>> =
+        crate::settings::get().xray.log_file.clone().map(spawn_file_writer);
+);
+
+/// Writes and rotates the log file on its own thread, so slow disks never
+/// hold up the runtime or the buffer lock. Stops at the first write error
+fn spawn_file_writer(path: String) -> SyncSender<String> {
+    let (sender, receiver) = mpsc::sync_channel::<String>(FILE_CHANNEL_SIZE);
+
+    std::thread::spawn(move || {
+        let mut file = RotatingFile::new(path);
+        for line in receiver {
+            if let Err(e) = file.write(&line) {
+                eprintln!("[necko-xray]: Failed to write Xray log file: {}", e);
+                return;
+            }
+        }
+    });
+
+    sender
+}
+
+struct RotatingFile {
+    path: String,
+    file: Option<File>,
+}
+
+impl RotatingFile {
+    fn new(path: String) -> Self {
+        Self { path, file: None }
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() >= LOG_FILE_MAX_SIZE) {
+            self.file = None;
+            for i in (1..LOG_FILE_KEEP).rev() {
+                let _ = fs::rename(format!("{}.{}", self.path, i),
+                                   format!("{}.{}", self.path, i + 1));
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?);
+        }
+
+        writeln!(self.file.as_mut().unwrap(), "{}", line)
+    }
+}
+
+fn push(text: String) {
+    let line = LogLine {
+        at: Utc::now(),
+        level: LogLevel::parse(&text),
+        text,
+    };
+
+    // off by default so `docker logs` only has the daemon's own output
+    if crate::settings::get().xray.log_stdout {
+        println!("[xray]: {}", line.text);
+    }
+
+    if let Some(file) = FILE_LINES.as_ref() {
+        let _ = file.try_send(line.text.clone());
+    }
+
+    let mut buffer = BUFFER.lock().unwrap();
+
+    if buffer.len() == BUFFER_LINES {
+        buffer.pop_front();
+    }
+    buffer.push_back(line.clone());
+
+    // sent under the lock so `stream` never misses or repeats a line
+    let _ = FOLLOWERS.send(line);
+}
+
+/// Reads one of the Xray pipes line by line until it closes
+pub(super) fn capture<R>(pipe: Option<R>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let Some(pipe) = pipe else { return };
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(pipe).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            push(line);
+        }
+    });
+}
+
+/// Writes buffered lines matching the filter to a control socket client,
+/// then keeps sending new ones while `follow` is set, the client is there
+/// and the daemon isn't stopping
+pub(super) async fn stream(
+    stream: &mut UnixStream,
+    filter: LogFilter,
+    mut stopped: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let (backlog, mut receiver) = {
+        let buffer = BUFFER.lock().unwrap();
+        let backlog: Vec<LogLine> = buffer
+            .iter()
+            .filter(|l| filter.matches(l))
+            .cloned()
+            .collect();
+
+        (backlog, FOLLOWERS.subscribe())
+    };
+
+    let (mut reader, mut writer) = stream.split();
+
+    for line in backlog {
+        write_frame(&mut writer, &Response::LogLine(line)).await?;
+    }
+
+    if !filter.follow {
+        return Ok(());
+    }
+
+    let mut closed = [0u8; 1];
+    loop {
+        let received = tokio::select! {
+            // the client never writes after its request, any read means it left
+            _ = reader.read(&mut closed) => return Ok(()),
+            _ = stopped.changed() => return Ok(()),
+            received = receiver.recv() => received,
+        };
+
+        match received {
+            Ok(line) if filter.matches(&line) => {
+                write_frame(&mut writer, &Response::LogLine(line)).await?;
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                let message = format!("... {} lines skipped", skipped);
+                write_frame(&mut writer, &Response::Message(message)).await?;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
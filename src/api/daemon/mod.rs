@@ -1,25 +1,76 @@
+pub mod accounting;
+pub mod accounts;
+pub mod auth;
+pub mod bans;
+pub mod enforce;
+mod listing;
 pub mod lock;
-
-use std::env;
-use crate::api::Request;
-use bincode::config::standard;
-use bincode::serde::{decode_from_slice, encode_to_vec};
+pub mod logs;
+pub mod online;
+pub mod protocol;
+pub mod rebuild;
+pub mod reload;
+pub mod supervisor;
+pub mod sync;
+pub mod wireguard;
+
+use crate::settings;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::api::{ApiError, ErrorCode, Request, Response};
 use std::process::Stdio;
 use sqlx::PgPool;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::process::Command;
+use tokio::process::{Child, Command};
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Duration;
+
+/// How long running control socket requests get to finish on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long Xray gets to exit after SIGTERM before it is killed
+const XRAY_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static!(
+    /// Profile the current config was generated from
+    static ref ACTIVE_PROFILE: Mutex<String> =
+        Mutex::new(saved_profile().unwrap_or_else(|| settings::get().xray.profile.clone()));
+);
+
+pub fn profile_path(profile: &str) -> String {
+    settings::get().profile_path(profile)
+}
+
+pub fn active_profile() -> String {
+    ACTIVE_PROFILE.lock().unwrap().clone()
+}
+
+/// Next to the installed config, so the profile picked by `reload <profile>`
+/// survives a daemon restart
+fn active_profile_path() -> String {
+    format!("{}.profile", settings::get().xray.config)
+}
+
+fn saved_profile() -> Option<String> {
+    let profile = std::fs::read_to_string(active_profile_path()).ok()?;
+    let profile = profile.trim();
+
+    (!profile.is_empty()).then(|| profile.to_string())
+}
+
+fn set_active_profile(profile: &str) {
+    *ACTIVE_PROFILE.lock().unwrap() = profile.to_string();
 
-const PROFILE: &str = "example.json"; // todo change to sql query
-pub const SOCKET_PATH: &str = "/tmp/necko-xray.sock";
-const XRAY_PID_FILE: &str = "/tmp/necko-xray-core.pid";
+    if let Err(e) = std::fs::write(active_profile_path(), profile) {
+        eprintln!("[necko-xray]: Failed to save the active profile: {}", e);
+    }
+}
 
 pub fn is_xray_running() -> bool {
-    if let Ok(pid_str) = std::fs::read_to_string(XRAY_PID_FILE) {
-        if let Ok(pid) = pid_str.trim().parse::<i32>() {
-            return lock::is_process_running(pid);
-        }
+    if let Ok(pid_str) = std::fs::read_to_string(&settings::get().xray.pid_file)
+        && let Ok(pid) = pid_str.trim().parse::<i32>() {
+        return lock::is_process_running(pid);
     }
     false
 }
@@ -27,89 +78,237 @@ pub fn is_xray_running() -> bool {
 pub async fn start() -> anyhow::Result<()> {
     println!("[necko-xray]: Starting daemon...");
 
-    let profile_path = format!("/etc/xray/profiles/{}", PROFILE);
-    let _ = if std::path::Path::new(&profile_path).exists() {
-        crate::config::generate_config_from_profile(Some(&profile_path))
+    // get postgres pool
+    let settings = settings::get();
+    let pool = crate::data::create_db_pool(
+        &settings.database_url()?, settings.database.max_connections).await?;
+    crate::data::postgres::migrations::migrate(&pool).await?;
+
+    let profile = active_profile();
+    let profile_path = profile_path(&profile);
+    let generated = if std::path::Path::new(&profile_path).exists() {
+        crate::config::generate_config_from_profile(Some(&profile_path)).await
     } else {
         eprintln!(
             "[necko-xray]: Cannot find {} profile ({})! Using empty profile",
-            PROFILE, profile_path
+            profile, profile_path
         );
-        crate::config::generate_config_from_profile(None)
+        crate::config::generate_config_from_profile(None).await
     };
+    if let Err(e) = generated {
+        eprintln!("[necko-xray]: Keeping the existing Xray config: {}", e);
+    }
+
+    if is_xray_running() {
+        replace_orphaned_xray(&pool).await;
+    }
 
-    // start xray
-    start_xray().await?;
+    // start xray and put database users back into it
+    match start_xray(&pool).await {
+        Ok(summary) => println!("[necko-xray]: {}", summary),
+        Err(e) => eprintln!("[necko-xray]: Failed to start Xray: {}", e),
+    }
 
-    // get postgres pool
-    let db_url = env::var("DATABASE_URL")
-        .unwrap_or(format!("postgresql://{}:{}@localhost:5432/{}",
-                           env::var("POSTGRES_USER")
-                               .map_err(|_| anyhow::anyhow!("POSTGRES_USER is not set"))?,
-                           env::var("POSTGRES_PASSWORD")
-                               .map_err(|_| anyhow::anyhow!("POSTGRES_PASSWORD is not set"))?,
-                           env::var("POSTGRES_DB")
-                               .map_err(|_| anyhow::anyhow!("POSTGRES_DB is not set"))?));
-    let pool = crate::data::create_db_pool(&db_url).await?;
-    crate::data::postgres::init_database(&pool).await?;
+    tokio::spawn(accounting::run(pool.clone()));
+    tokio::spawn(enforce::run_expiry_timer(pool.clone()));
+    tokio::spawn(online::run(pool.clone()));
 
     // start api server
-    tokio::spawn(async move {
-        if let Err(e) = run_api_server(pool.clone()).await {
-            eprintln!("[necko-xray]: API Server error: {}", e);
+    let (stop_api, api_stopped) = watch::channel(false);
+    let api_server = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            if let Err(e) = run_api_server(pool, api_stopped).await {
+                eprintln!("[necko-xray]: API Server error: {}", e);
+            }
         }
     });
 
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                println!("[necko-xray]: Received SIGTERM (shutting down daemon)");
+                break;
+            }
+            _ = sigint.recv() => {
+                println!("[necko-xray]: Received SIGINT (shutting down daemon)");
+                break;
+            }
+            // profile only, settings are read once on start
+            _ = sighup.recv() => {
+                println!("[necko-xray]: Received SIGHUP (reloading profile)");
+                match reload::reload_profile(&pool, None).await {
+                    Ok(summary) => println!("[necko-xray]: {}", summary),
+                    Err(e) => eprintln!("[necko-xray]: Reload failed: {:#}", e),
+                }
+            }
+        }
+    }
+
+    shutdown(pool, stop_api, api_server).await;
+    Ok(())
+}
 
-    tokio::select! {
-        _ = sigterm.recv() => println!("[necko-xray]: Received SIGTERM (shutting down daemon)"),
-        _ = sigint.recv() => println!("[necko-xray]: Received SIGINT (shutting down daemon)"),
+/// Stops in an order that loses nothing: no new requests, running ones
+/// finish, traffic is saved while Xray still answers, then Xray and the pool go
+async fn shutdown(pool: PgPool, stop_api: watch::Sender<bool>, api_server: JoinHandle<()>) {
+    let _ = stop_api.send(true);
+    let _ = api_server.await;
+
+    if is_xray_running() {
+        match accounting::collect(&pool).await {
+            Ok(users) => println!("[necko-xray]: Saved traffic of {} users", users),
+            Err(e) => eprintln!("[necko-xray]: Failed to save traffic: {:#}", e),
+        }
+
+        if let Err(e) = stop_xray(XRAY_STOP_TIMEOUT).await {
+            eprintln!("[necko-xray]: Failed to stop Xray: {}", e);
+        }
     }
 
+    pool.close().await;
     cleanup();
+    println!("[necko-xray]: Daemon stopped");
+}
+
+/// Xray left running by a daemon that crashed: nothing reaps it or reads its
+/// logs, so its traffic is saved under the epoch it ran with and it is
+/// stopped for `start_xray` to put a supervised one in its place
+async fn replace_orphaned_xray(pool: &PgPool) {
+    println!("[necko-xray]: Found Xray left running by a previous daemon, replacing it");
+
+    match supervisor::adopt_epoch() {
+        Some(_) => save_traffic(pool).await,
+        None => eprintln!("[necko-xray]: No saved epoch for the running Xray, \
+            traffic since the last collection is lost"),
+    }
+
+    if let Err(e) = stop_xray(XRAY_STOP_TIMEOUT).await {
+        eprintln!("[necko-xray]: Failed to stop the previous Xray: {}", e);
+    }
+}
+
+/// Collects traffic before Xray goes away with its counters
+pub async fn save_traffic(pool: &PgPool) {
+    if let Err(e) = accounting::collect(pool).await {
+        eprintln!("[necko-xray]: Failed to save traffic: {:#}", e);
+    }
+}
+
+/// SIGTERM, then SIGKILL if Xray is still there after `timeout`
+async fn stop_xray(timeout: Duration) -> anyhow::Result<()> {
+    let pid = std::fs::read_to_string(&settings::get().xray.pid_file)?
+        .trim()
+        .parse::<i32>()?;
+
+    stop().await?;
+    if wait_for_xray_exit(timeout).await {
+        return Ok(());
+    }
+
+    println!("[necko-xray]: Xray did not exit in {}s, killing it", timeout.as_secs());
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+
+        kill(Pid::from_raw(pid), Signal::SIGKILL)?;
+    }
+
+    if !wait_for_xray_exit(Duration::from_secs(1)).await {
+        anyhow::bail!("Xray (PID: {}) is still running", pid);
+    }
+
     Ok(())
 }
 
-pub async fn start_xray() -> anyhow::Result<()> {
+async fn wait_for_xray_exit(timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while is_xray_running() {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+/// Starts Xray and re-adds all active users, since a fresh core only knows
+/// the clients written in its config file. Stops it again when its API
+/// never comes up, so a failed start leaves nothing running
+pub async fn start_xray(pool: &PgPool) -> anyhow::Result<sync::SyncSummary> {
     if is_xray_running() {
         anyhow::bail!("Xray is already running");
     }
 
-    let mut xray = Command::new("/usr/local/bin/xray")
+    let xray = spawn_xray()?;
+    let generation = supervisor::want_running();
+    tokio::spawn(supervisor::supervise(xray, pool.clone(), generation));
+
+    match sync::sync_users(pool).await {
+        Ok(summary) => Ok(summary),
+        Err(e) => {
+            // an Xray without its users must not be left running behind an error
+            if is_xray_running() {
+                let _ = stop().await;
+            }
+            Err(e.context("Xray was stopped again"))
+        }
+    }
+}
+
+/// Stops Xray, waits for the supervisor to reap it and starts it again
+pub async fn restart_xray(pool: &PgPool) -> anyhow::Result<sync::SyncSummary> {
+    save_traffic(pool).await;
+    stop().await?;
+
+    if !wait_for_xray_exit(Duration::from_secs(5)).await {
+        anyhow::bail!("Failed to stop Xray process");
+    }
+
+    start_xray(pool).await
+}
+
+fn spawn_xray() -> anyhow::Result<Child> {
+    let settings = &settings::get().xray;
+    let mut xray = Command::new(&settings.bin)
         .arg("-config")
-        .arg("/etc/xray/config.json")
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
+        .arg(&settings.config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .kill_on_drop(true)
         .spawn()
-        .expect("Failed to start Xray process");
+        .map_err(|e| anyhow::anyhow!("Failed to start Xray process: {}", e))?;
 
-    let xray_pid = xray.id().unwrap();
+    let xray_pid = xray.id()
+        .ok_or_else(|| anyhow::anyhow!("Xray exited right after start"))?;
     println!("[necko-xray]: Xray started with PID: {}", xray_pid);
 
-    std::fs::write(XRAY_PID_FILE, xray_pid.to_string())?;
+    logs::capture(xray.stdout.take());
+    logs::capture(xray.stderr.take());
 
-    tokio::spawn(async move {
-        match xray.wait().await {
-            Ok(status) => println!("[necko-xray]: Xray exited with status: {}", status),
-            Err(e) => eprintln!("[necko-xray]: Failed to wait for Xray: {}", e),
-        }
-        let _ = std::fs::remove_file(XRAY_PID_FILE);
-    });
+    std::fs::write(&settings.pid_file, xray_pid.to_string())?;
+    supervisor::mark_started(xray_pid);
 
-    Ok(())
+    Ok(xray)
 }
 
 fn cleanup() {
-    let _ = std::fs::remove_file(SOCKET_PATH);
-    let _ = std::fs::remove_file(XRAY_PID_FILE);
+    let settings = settings::get();
+    let _ = std::fs::remove_file(&settings.daemon.socket_path);
+    let _ = std::fs::remove_file(&settings.xray.pid_file);
+    let _ = std::fs::remove_file(supervisor::epoch_path());
     lock::release_lock();
 }
 
 pub async fn stop() -> anyhow::Result<()> {
-    let pid_str = std::fs::read_to_string(XRAY_PID_FILE)
+    supervisor::want_stopped();
+
+    let pid_str = std::fs::read_to_string(&settings::get().xray.pid_file)
         .map_err(|_| anyhow::anyhow!("Xray daemon is not running"))?;
     let pid: i32 = pid_str.trim().parse()?;
 
@@ -127,51 +326,205 @@ pub async fn stop() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_api_server(pool: PgPool) -> anyhow::Result<()> {
-    let _ = std::fs::remove_file(SOCKET_PATH);
+async fn run_api_server(pool: PgPool, mut stopped: watch::Receiver<bool>) -> anyhow::Result<()> {
+    let socket_path = &settings::get().daemon.socket_path;
+    let _ = std::fs::remove_file(socket_path);
 
-    let listener = UnixListener::bind(SOCKET_PATH)?;
-    println!("[necko-xray]: API Server listening on {}", SOCKET_PATH);
+    let listener = UnixListener::bind(socket_path)?;
+    auth::secure_socket(socket_path)?;
+    println!("[necko-xray]: API Server listening on {}", socket_path);
 
+    let mut handlers = JoinSet::new();
     loop {
-        let (mut stream, _) = listener.accept().await?;
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = stopped.changed() => break,
+        };
 
-        let pool = pool.clone();
-        tokio::spawn(async move {
-            let mut len_buf = [0u8; 4];
-            if stream.read_exact(&mut len_buf).await.is_err() {
-                return;
-            }
-            let len = u32::from_be_bytes(len_buf) as usize;
+        // forget finished handlers so the set doesn't grow forever
+        while handlers.try_join_next().is_some() {}
 
-            let mut buf = vec![0u8; len];
-            if stream.read_exact(&mut buf).await.is_err() {
-                return;
-            }
+        handlers.spawn(handle_connection(stream, pool.clone(), stopped.clone()));
+    }
 
-            let result: anyhow::Result<String> = {
-                let (req, _read): (Request, usize) =
-                    decode_from_slice(&buf, standard()).unwrap();
-                crate::api::handle_command(pool, req).await
-            };
+    drop(listener);
+    let _ = std::fs::remove_file(socket_path);
 
-            let response = result.unwrap_or_else(|e| e.to_string());
-            let _ = stream.write_all(response.as_bytes()).await;
-        });
+    if !handlers.is_empty() {
+        println!("[necko-xray]: Waiting for {} running requests", handlers.len());
+    }
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while handlers.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        eprintln!("[necko-xray]: Requests still running after {}s, aborting them",
+                  DRAIN_TIMEOUT.as_secs());
+        handlers.abort_all();
     }
+
+    Ok(())
 }
 
-pub async fn send_request(request: Request) -> anyhow::Result<String> {
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
+async fn handle_connection(
+    mut stream: UnixStream,
+    pool: PgPool,
+    stopped: watch::Receiver<bool>,
+) {
+    let Ok(cred) = stream.peer_cred() else { return };
+
+    if !accept_hello(&mut stream).await {
+        return;
+    }
+
+    let request = tokio::time::timeout(
+        protocol::READ_TIMEOUT,
+        protocol::read_frame::<_, Request>(&mut stream, protocol::MAX_REQUEST_SIZE),
+    ).await;
+
+    let request = match request {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            let error = ApiError::bad_request(format!("Invalid request: {}", e));
+            let _ = protocol::write_frame(
+                &mut stream, &Response::from_error(&error.into())).await;
+            return;
+        }
+        Err(_) => {
+            let error = ApiError::bad_request("Timed out reading request");
+            let _ = protocol::write_frame(
+                &mut stream, &Response::from_error(&error.into())).await;
+            return;
+        }
+    };
+
+    // the handshake only compares protocol versions, access is checked
+    // after it so a denied client can decode the error it gets
+    let required = auth::Access::required(&request);
+    if auth::Access::granted(&cred).is_none_or(|granted| required > granted) {
+        println!("[necko-xray]: Denied `{}` to uid {} gid {}{}",
+                 request.kind(), cred.uid(), cred.gid(), describe_pid(&cred));
+        let error = ApiError::new(ErrorCode::PermissionDenied, format!(
+            "uid {} is not allowed to send `{}`", cred.uid(), request.kind()));
+        let _ = protocol::write_frame(
+            &mut stream, &Response::from_error(&error.into())).await;
+        return;
+    }
 
-    let bytes = encode_to_vec(&request, standard())?;
-    let len = (bytes.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
-    stream.write_all(&bytes).await?;
-    stream.flush().await?;
+    if let Request::Logs(filter) = request {
+        let _ = logs::stream(&mut stream, filter, stopped).await;
+        return;
+    }
 
-    let mut response = String::new();
-    stream.read_to_string(&mut response).await?;
+    if let Request::ListUsers(filter) = request {
+        let _ = listing::stream(&mut stream, &pool, filter).await;
+        return;
+    }
 
-    Ok(response)
+    let response = crate::api::handle_command(pool, request)
+        .await
+        .unwrap_or_else(|e| Response::from_error(&e));
+    let _ = protocol::write_frame(&mut stream, &response).await;
+}
+
+fn describe_pid(cred: &tokio::net::unix::UCred) -> String {
+    cred.pid().map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+}
+
+/// Answers the client's hello with ours, `false` when the connection
+/// can't go on (the client then reports the mismatch itself)
+async fn accept_hello(stream: &mut UnixStream) -> bool {
+    let hello = tokio::time::timeout(
+        protocol::READ_TIMEOUT, protocol::read_hello(stream)).await;
+
+    let hello = match hello {
+        Ok(Ok(Some(hello))) => hello,
+        Ok(Ok(None)) => {
+            // a CLI from before the handshake still understands a Response frame
+            let error = ApiError::bad_request(format!(
+                "This CLI is older than the daemon (v{}), update it",
+                env!("CARGO_PKG_VERSION")));
+            let _ = protocol::write_frame(stream, &Response::from_error(&error.into())).await;
+            return false;
+        }
+        Ok(Err(_)) | Err(_) => return false,
+    };
+
+    let ours = protocol::Hello::new(
+        Request::KINDS.iter().map(|kind| kind.to_string()).collect());
+    if protocol::write_hello(stream, &ours).await.is_err() {
+        return false;
+    }
+
+    if hello.protocol_version != protocol::PROTOCOL_VERSION {
+        println!("[necko-xray]: Rejected CLI v{} speaking protocol {} (daemon speaks {})",
+                 hello.version, hello.protocol_version, protocol::PROTOCOL_VERSION);
+        return false;
+    }
+
+    true
+}
+
+/// Connects to the daemon and exchanges hellos, failing with a readable
+/// error when the two binaries can't understand each other
+pub async fn connect() -> anyhow::Result<(UnixStream, protocol::Hello)> {
+    let mut stream = UnixStream::connect(&settings::get().daemon.socket_path).await?;
+
+    protocol::write_hello(&mut stream, &protocol::Hello::new(vec![])).await?;
+
+    let hello = tokio::time::timeout(protocol::READ_TIMEOUT, protocol::read_hello(&mut stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for the daemon handshake"))?
+        .map_err(|e| anyhow::anyhow!("{} (the daemon may be older than this CLI, \
+            restart it)", e))?
+        .ok_or_else(|| anyhow::anyhow!("The daemon is older than this CLI (v{}), restart it",
+                                       env!("CARGO_PKG_VERSION")))?;
+
+    if hello.protocol_version != protocol::PROTOCOL_VERSION {
+        anyhow::bail!("CLI v{} speaks protocol {}, but the daemon v{} speaks {}; \
+            use the same version for both",
+                      env!("CARGO_PKG_VERSION"), protocol::PROTOCOL_VERSION,
+                      hello.version, hello.protocol_version);
+    }
+
+    Ok((stream, hello))
+}
+
+async fn connect_for(request: &Request) -> anyhow::Result<UnixStream> {
+    let (stream, hello) = connect().await?;
+
+    if !hello.capabilities.iter().any(|kind| kind == request.kind()) {
+        anyhow::bail!("The daemon v{} does not support `{}`, restart it to use this CLI v{}",
+                      hello.version, request.kind(), env!("CARGO_PKG_VERSION"));
+    }
+
+    Ok(stream)
+}
+
+pub async fn send_request(request: Request) -> anyhow::Result<Response> {
+    let mut stream = connect_for(&request).await?;
+
+    protocol::write_frame(&mut stream, &request).await?;
+
+    protocol::read_frame(&mut stream, protocol::MAX_RESPONSE_SIZE)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Daemon closed the connection without a response"))
+}
+
+/// Like `send_request`, but hands every response frame over as it arrives,
+/// for requests that keep streaming (e.g. `logs --follow`)
+pub async fn stream_request(
+    request: Request,
+    mut on_response: impl FnMut(Response),
+) -> anyhow::Result<()> {
+    let mut stream = connect_for(&request).await?;
+
+    protocol::write_frame(&mut stream, &request).await?;
+
+    while let Some(response) =
+        protocol::read_frame(&mut stream, protocol::MAX_RESPONSE_SIZE).await? {
+        on_response(response);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{TimeDelta, Utc};
+use sqlx::PgPool;
+use tokio::time::{Duration, MissedTickBehavior};
+use uuid::Uuid;
+use crate::config::BLOCK_OUTBOUND;
+use crate::data::postgres::types::{IpLimitPunishment, User};
+use crate::Client;
+
+/// Polls the online IPs of every user in Xray every `online.poll_interval`
+/// seconds, records sessions, keeps `ip_list` up to date and punishes
+/// users over `ip_limit`
+pub async fn run(pool: PgPool) {
+    let interval = Duration::from_secs(crate::settings::get().online.poll_interval.max(1));
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        if !super::is_xray_running() {
+            continue;
+        }
+
+        if let Err(e) = poll(&pool).await {
+            eprintln!("[necko-xray]: Failed to poll online users: {:#}", e);
+        }
+    }
+}
+
+async fn poll(pool: &PgPool) -> anyhow::Result<()> {
+    let client = Client::connect().await?;
+
+    super::bans::expire(pool, &client).await?;
+
+    let users = crate::data::postgres::get_active_users(pool).await?;
+    let bans = crate::data::postgres::get_active_ip_bans(pool).await?;
+
+    // all read before anything is written, an API that stops answering
+    // must not look like everybody went offline
+    let online_users: HashSet<String> = match client.online_users().await {
+        Ok(emails) => emails.into_iter().collect(),
+        // Xray without GetAllOnlineUsers, every user has to be asked
+        Err(e) if is_unimplemented(&e) => users.iter().map(|u| u.email.clone()).collect(),
+        Err(e) => return Err(e.context("Failed to list online users, skipping this poll")),
+    };
+
+    let mut online = HashMap::new();
+    for user in users.iter().filter(|u| online_users.contains(&u.email)) {
+        match client.user_online_ip_list(&user.email).await {
+            Ok(ips) => { online.insert(user.id, ips); }
+            Err(e) if is_offline(&e) => {}
+            Err(e) => return Err(e.context("Failed to read online IPs, skipping this poll")),
+        }
+    }
+
+    let at = Utc::now();
+    let seen: Vec<(Uuid, String)> = online
+        .iter()
+        .flat_map(|(id, ips)| ips.keys().map(|ip| (*id, ip.clone())))
+        .collect();
+    if let Err(e) = crate::data::postgres::record_sessions(pool, &seen, at, session_gap()).await {
+        eprintln!("[necko-xray]: Failed to record sessions: {}", e);
+    }
+
+    let offline = HashMap::new();
+    let mut ip_lists = HashMap::new();
+    let mut over_limit = Vec::new();
+    for user in &users {
+        let old = user.ip_list.as_ref().map(|l| l.0.clone()).unwrap_or_default();
+        let user_online = online.get(&user.id).unwrap_or(&offline);
+        let ip_list = merge_ip_list(&old, user_online, user.ip_expire_after, at.timestamp());
+        if ip_list != old {
+            ip_lists.insert(user.id, ip_list.clone());
+        }
+
+        let banned: HashSet<&str> = bans
+            .iter()
+            .filter(|b| b.user_id == user.id)
+            .map(|b| b.ip.as_str())
+            .collect();
+        let counted = ip_list.keys().filter(|ip| !banned.contains(ip.as_str())).count() as i64;
+
+        if user.ip_limit > 0 && counted > user.ip_limit {
+            over_limit.push((user, old, ip_list, banned, counted));
+        }
+    }
+
+    if let Err(e) = crate::data::postgres::set_ip_lists(pool, &ip_lists).await {
+        eprintln!("[necko-xray]: Failed to save IP lists: {}", e);
+    }
+
+    for (user, old, ip_list, banned, counted) in over_limit {
+        if let Err(e) = punish(pool, &client, user, &old, ip_list, &banned, counted).await {
+            eprintln!("[necko-xray]: Failed to punish user {}: {:#}", user.email, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Xray has no online map for users that never connected and answers
+/// with "<name> not found."
+fn is_offline(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<tonic::Status>().is_some_and(|s| s.message().ends_with("not found."))
+}
+
+fn is_unimplemented(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<tonic::Status>().is_some_and(|s| s.code() == tonic::Code::Unimplemented)
+}
+
+/// An IP missing from this many seconds of polls starts a new session
+fn session_gap() -> i64 {
+    (crate::settings::get().online.poll_interval as i64 * 3).max(60)
+}
+
+async fn punish(
+    pool: &PgPool,
+    client: &Client,
+    user: &User,
+    old: &HashMap<String, i64>,
+    mut ip_list: HashMap<String, i64>,
+    banned: &HashSet<&str>,
+    counted: i64,
+) -> anyhow::Result<()> {
+    let punishment = user.ip_limit_punishment
+        .as_ref()
+        .map(|p| p.0.clone())
+        .unwrap_or(IpLimitPunishment::Nothing);
+
+    match punishment {
+        IpLimitPunishment::Nothing => {}
+        IpLimitPunishment::SuspendUser { time } => {
+            let until = Utc::now() + TimeDelta::seconds(time);
+            crate::data::postgres::suspend_user(pool, user.id, until).await?;
+            println!("[necko-xray]: User {} uses {} IPs (limit {}), suspended until {}",
+                     user.email, counted, user.ip_limit, until);
+
+            super::enforce::reconcile(pool).await?;
+            super::enforce::expiry_changed();
+        }
+        IpLimitPunishment::BanLastIp { time } => {
+            if !crate::config::has_block_outbound() {
+                anyhow::bail!("Cannot ban IPs of {}: the profile has no outbounds, so there is \
+                    no {} outbound to route them to; add one or use suspend", user.email, BLOCK_OUTBOUND);
+            }
+
+            let Some(ip) = newest_ip(old, &ip_list, banned) else { return Ok(()) };
+
+            let until = Utc::now() + TimeDelta::seconds(time);
+            let ban = crate::data::postgres::create_ip_ban(pool, user.id, &ip, until).await?;
+            println!("[necko-xray]: User {} uses {} IPs (limit {}), {} banned until {} ({})",
+                     user.email, counted, user.ip_limit, ip, until, ban.rule_tag());
+
+            super::bans::apply(pool, client).await?;
+
+            ip_list.remove(&ip);
+            crate::data::postgres::set_ip_list(pool, user.id, &ip_list).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the online IPs (ip -> last seen, unix seconds) to the known ones and
+/// drops those not seen for `expire_after` seconds (0 = keep forever)
+fn merge_ip_list(
+    known: &HashMap<String, i64>,
+    online: &HashMap<String, i64>,
+    expire_after: i64,
+    now: i64,
+) -> HashMap<String, i64> {
+    let mut merged = known.clone();
+    for (ip, seen) in online {
+        let entry = merged.entry(ip.clone()).or_insert(*seen);
+        *entry = (*entry).max(*seen);
+    }
+
+    if expire_after > 0 {
+        merged.retain(|_, seen| now - *seen <= expire_after);
+    }
+
+    merged
+}
+
+/// The IP that pushed the user over the limit: one that just showed up if
+/// any, otherwise the most recently seen, never one that is banned already
+fn newest_ip(
+    old: &HashMap<String, i64>,
+    ip_list: &HashMap<String, i64>,
+    banned: &HashSet<&str>,
+) -> Option<String> {
+    ip_list
+        .iter()
+        .filter(|(ip, _)| !banned.contains(ip.as_str()))
+        .max_by_key(|(ip, seen)| (!old.contains_key(*ip), **seen, (*ip).clone()))
+        .map(|(ip, _)| ip.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_list_test() {
+        let known = HashMap::from([("1.1.1.1".to_string(), 100), ("2.2.2.2".to_string(), 50)]);
+        let online = HashMap::from([("1.1.1.1".to_string(), 190), ("3.3.3.3".to_string(), 150)]);
+
+        let merged = merge_ip_list(&known, &online, 100, 200);
+        assert_eq!(merged, HashMap::from([
+            ("1.1.1.1".to_string(), 190),
+            ("3.3.3.3".to_string(), 150),
+        ]));
+        assert_eq!(merge_ip_list(&known, &online, 0, 200).len(), 3);
+
+        // 3.3.3.3 is new even though 1.1.1.1 was seen later
+        assert_eq!(newest_ip(&known, &merged, &HashSet::new()).as_deref(), Some("3.3.3.3"));
+        assert_eq!(newest_ip(&known, &merged, &HashSet::from(["3.3.3.3"])).as_deref(),
+                   Some("1.1.1.1"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::Duration;
+use bincode::config::standard;
+use bincode::serde::{decode_from_slice, encode_to_vec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Requests are small, anything bigger is garbage or abuse
+pub const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+pub const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+/// How long a client may take to send its request
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bump whenever anything bincode encodes on the socket changes: a variant
+/// of `Request` or `Response`, a field or enum value of the types they
+/// carry. bincode has no field names, so any such change breaks decoding
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// First bytes of every connection, lets the daemon tell a client speaking
+/// the handshake apart from a pre-handshake CLI
+pub const MAGIC: [u8; 4] = *b"NXRY";
+
+/// Exchanged right after connecting: the client sends its own, the daemon
+/// answers with its version and the request kinds it handles.
+/// Never change this struct, it is how mismatches get detected at all
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Package version, for error messages
+    pub version: String,
+    /// `Request::kind()`s the daemon supports, empty from clients
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: Vec<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities,
+        }
+    }
+}
+
+/// Every message on the control socket is a big-endian u32 length followed
+/// by that many bytes of bincode
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let bytes = encode_to_vec(value, standard())?;
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| anyhow::anyhow!("Frame of {} bytes is too big", bytes.len()))?;
+
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads one frame, `None` when the peer closed the connection before it
+pub async fn read_frame<R, T>(reader: &mut R, max_size: usize) -> anyhow::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_size {
+        anyhow::bail!("Frame of {} bytes exceeds the {} bytes limit", len, max_size);
+    }
+
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+
+    let (value, _read): (T, usize) = decode_from_slice(&buf, standard())
+        .map_err(|e| anyhow::anyhow!("Malformed frame: {}", e))?;
+
+    Ok(Some(value))
+}
+
+/// Sends `MAGIC` followed by the hello frame
+pub async fn write_hello<W>(writer: &mut W, hello: &Hello) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&MAGIC).await?;
+    write_frame(writer, hello).await
+}
+
+/// Reads the peer's hello, `None` when it does not start with `MAGIC`,
+/// i.e. the peer was built before the handshake existed
+pub async fn read_hello<R>(reader: &mut R) -> anyhow::Result<Option<Hello>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await
+        .map_err(|e| anyhow::anyhow!("Connection closed during handshake: {}", e))?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+
+    read_frame(reader, MAX_REQUEST_SIZE)
+        .await?
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Connection closed during handshake"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hello_test() {
+        let (mut client, mut daemon) = tokio::io::duplex(1024);
+
+        write_hello(&mut client, &Hello::new(vec!["StartXray".into()])).await.unwrap();
+        let hello = read_hello(&mut daemon).await.unwrap().unwrap();
+        assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(hello.capabilities, vec!["StartXray".to_string()]);
+
+        // a pre-handshake peer starts with a frame length instead
+        write_frame(&mut client, &"request").await.unwrap();
+        assert!(read_hello(&mut daemon).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prost::Message;
+use sqlx::PgPool;
+use crate::proto::app::proxyman::command::{AddInboundRequest, RemoveInboundRequest};
+use crate::proto::common::serial::TypedMessage;
+use crate::proto::proxy::{http, socks};
+use crate::Client;
+
+/// Protocols whose users Xray only takes from the inbound's own settings.
+/// Their inbounds are recreated with the users of the database instead of
+/// going through AlterInbound, so any change to who is on such an inbound
+/// drops every live connection on it
+pub const REBUILT_PROTOCOLS: &[&str] = &["wireguard", "socks", "http"];
+
+lazy_static!(
+    /// Runs from user requests and the accounting tick, one at a time so
+    /// two WireGuard users never get the same address
+    static ref REFRESHING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+
+    /// What was last put into each inbound, sorted: (public key, address)
+    /// for WireGuard peers, (username, password) for SOCKS and HTTP
+    static ref APPLIED: std::sync::Mutex<HashMap<String, Vec<(String, String)>>> =
+        std::sync::Mutex::new(HashMap::new());
+);
+
+/// Gives every WireGuard user a peer and, with Xray running, recreates the
+/// inbounds whose set of enabled users changed
+pub async fn refresh(pool: &PgPool) -> anyhow::Result<()> {
+    let client = if super::is_xray_running() {
+        Some(Client::connect().await?)
+    } else {
+        None
+    };
+
+    refresh_with(pool, client.as_ref()).await
+}
+
+/// Puts the users into freshly created inbounds, `None` after a (re)start
+/// of the core when all of them are new
+pub async fn restore(
+    pool: &PgPool,
+    client: &Client,
+    only: Option<&[String]>,
+) -> anyhow::Result<()> {
+    {
+        let mut applied = APPLIED.lock().unwrap();
+        match only {
+            Some(tags) => tags.iter().for_each(|tag| { applied.remove(tag); }),
+            None => applied.clear(),
+        }
+    }
+
+    refresh_with(pool, Some(client)).await
+}
+
+async fn refresh_with(pool: &PgPool, client: Option<&Client>) -> anyhow::Result<()> {
+    let _guard = REFRESHING.lock().await;
+
+    let inbounds = crate::config::live_inbounds();
+    let mut rebuilt: Vec<(&String, &str)> = inbounds
+        .iter()
+        .filter(|(_, i)| REBUILT_PROTOCOLS.contains(&i.protocol.as_str()))
+        .map(|(tag, i)| (tag, i.protocol.as_str()))
+        .collect();
+    rebuilt.sort();
+
+    let wireguard: Vec<&String> = rebuilt.iter()
+        .filter(|(_, protocol)| *protocol == "wireguard")
+        .map(|(tag, _)| *tag)
+        .collect();
+    super::wireguard::allocate(pool, &wireguard).await?;
+
+    let Some(client) = client else { return Ok(()) };
+
+    let mut changed = Vec::new();
+    for (tag, protocol) in rebuilt {
+        let mut wanted = match protocol {
+            "wireguard" => super::wireguard::enabled_peers(pool, tag).await?,
+            _ => crate::data::postgres::get_inbound_accounts(pool, tag).await?,
+        };
+        wanted.sort();
+
+        // an inbound nobody was put into still matches the profile
+        let applied = APPLIED.lock().unwrap().get(tag).cloned().unwrap_or_default();
+        if applied != wanted {
+            changed.push((tag, protocol, wanted));
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let config = crate::config::current_config_protobuf().await?;
+    let mut handler = client.handler();
+
+    for (tag, protocol, wanted) in changed {
+        let mut inbound = config.inbound
+            .iter()
+            .find(|i| &i.tag == tag)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Inbound {} missing in converted config", tag))?;
+
+        let settings = inbound.proxy_settings
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Inbound {} has no settings", tag))?;
+        inbound.proxy_settings = Some(match protocol {
+            "wireguard" => super::wireguard::with_peers(settings, &wanted)?,
+            _ => with_accounts(protocol, settings, &wanted)?,
+        });
+
+        if let Err(status) = handler.remove_inbound(RemoveInboundRequest { tag: tag.clone() }).await
+            && !is_missing_inbound(&status) {
+            return Err(status).with_context(|| format!("Failed to remove inbound {}", tag));
+        }
+        handler.add_inbound(AddInboundRequest { inbound: Some(inbound) })
+            .await
+            .with_context(|| format!("Failed to add inbound {}", tag))?;
+
+        println!("[necko-xray]: Recreated {} inbound {} with {} users", protocol, tag, wanted.len());
+        APPLIED.lock().unwrap().insert(tag.clone(), wanted);
+    }
+
+    Ok(())
+}
+
+/// Xray answers RemoveInbound for an unknown tag with common.ErrNoClue
+fn is_missing_inbound(status: &tonic::Status) -> bool {
+    status.message().contains("not enough information for making a decision")
+}
+
+/// SOCKS or HTTP server settings with `accounts` (username, password)
+/// added to those of the profile. SOCKS is switched to password auth once
+/// it has users, an inbound without it would let anyone in
+fn with_accounts(
+    protocol: &str,
+    settings: &TypedMessage,
+    accounts: &[(String, String)],
+) -> anyhow::Result<TypedMessage> {
+    let value = match protocol {
+        "socks" => {
+            let mut config = socks::ServerConfig::decode(settings.value.as_slice())?;
+            if !accounts.is_empty() {
+                config.accounts.extend(accounts.iter().cloned());
+                config.auth_type = socks::AuthType::Password as i32;
+            }
+            config.encode_to_vec()
+        }
+        "http" => {
+            let mut config = http::ServerConfig::decode(settings.value.as_slice())?;
+            config.accounts.extend(accounts.iter().cloned());
+            config.encode_to_vec()
+        }
+        protocol => anyhow::bail!("{} inbounds have no accounts map", protocol),
+    };
+
+    Ok(TypedMessage { r#type: settings.r#type.clone(), value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::common::serial;
+
+    #[test]
+    fn with_accounts_test() {
+        let users = vec![("a@example.com".to_string(), "secret".to_string())];
+
+        let profile = socks::ServerConfig {
+            accounts: HashMap::from([("static".to_string(), "pass".to_string())]),
+            udp_enabled: true,
+            ..Default::default()
+        };
+        let settings = serial::to_typed_message(&profile, "xray.proxy.socks.ServerConfig");
+
+        let rebuilt = with_accounts("socks", &settings, &users).unwrap();
+        let config = socks::ServerConfig::decode(rebuilt.value.as_slice()).unwrap();
+        assert_eq!(rebuilt.r#type, "xray.proxy.socks.ServerConfig");
+        assert_eq!(config.auth_type, socks::AuthType::Password as i32);
+        assert_eq!(config.accounts["static"], "pass");
+        assert_eq!(config.accounts["a@example.com"], "secret");
+        assert!(config.udp_enabled);
+
+        let settings = serial::to_typed_message(&socks::ServerConfig::default(), "xray.proxy.socks.ServerConfig");
+        let rebuilt = with_accounts("socks", &settings, &[]).unwrap();
+        let config = socks::ServerConfig::decode(rebuilt.value.as_slice()).unwrap();
+        assert_eq!(config.auth_type, socks::AuthType::NoAuth as i32);
+
+        let settings = serial::to_typed_message(
+            &http::ServerConfig { allow_transparent: true, ..Default::default() },
+            "xray.proxy.http.ServerConfig");
+
+        let rebuilt = with_accounts("http", &settings, &users).unwrap();
+        let config = http::ServerConfig::decode(rebuilt.value.as_slice()).unwrap();
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts["a@example.com"], "secret");
+        assert!(config.allow_transparent);
+
+        assert!(with_accounts("vless", &settings, &users).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+use anyhow::Context;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use crate::config::diff::{diff, ConfigDiff};
+use crate::proto::app::proxyman::command::{
+    AddInboundRequest, AddOutboundRequest, RemoveInboundRequest, RemoveOutboundRequest,
+};
+use crate::proto::app::router::command::AddRuleRequest;
+use crate::Client;
+use super::sync::SyncSummary;
+
+pub struct ReloadSummary {
+    /// What was applied, e.g. `Profile example.json`
+    pub source: String,
+    pub diff: ConfigDiff,
+    pub xray_running: bool,
+    /// Set when the changes required a full restart
+    pub restarted: Option<SyncSummary>,
+    /// Users re-added to recreated inbounds
+    pub resynced: Option<SyncSummary>,
+}
+
+impl fmt::Display for ReloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.diff.is_empty() {
+            return write!(f, "{} applied, nothing changed", self.source);
+        }
+
+        if !self.xray_running {
+            return write!(f, "{} written, Xray is not running", self.source);
+        }
+
+        if let Some(sync) = &self.restarted {
+            return write!(f, "{} applied with a restart (changed: {})\n{}",
+                          self.source, self.diff.restart_sections.join(", "), sync);
+        }
+
+        write!(f, "{} applied live", self.source)?;
+        let d = &self.diff;
+        for (name, list) in [
+            ("inbounds removed", &d.inbounds_removed),
+            ("inbounds added", &d.inbounds_added),
+            ("outbounds removed", &d.outbounds_removed),
+            ("outbounds added", &d.outbounds_added),
+        ] {
+            if !list.is_empty() {
+                write!(f, "\n  {}: {}", name, list.join(", "))?;
+            }
+        }
+        if d.routing_changed {
+            write!(f, "\n  routing rules updated")?;
+        }
+        if let Some(sync) = &self.resynced {
+            write!(f, "\n{}", sync)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Regenerates the Xray config from a profile (the active one by default)
+/// and applies the difference to the running core, restarting it only when
+/// a changed section cannot be updated through the API
+pub async fn reload_profile(
+    pool: &PgPool,
+    profile: Option<String>,
+) -> anyhow::Result<ReloadSummary> {
+    let profile = profile.unwrap_or_else(super::active_profile);
+    let path = super::profile_path(&profile);
+    if !std::path::Path::new(&path).exists() {
+        anyhow::bail!("Cannot find {} profile ({})", profile, path);
+    }
+
+    let old = crate::config::read_current_config().unwrap_or_else(|_| json!({}));
+    let new = crate::config::generate_config_from_profile(Some(&path)).await?;
+    super::set_active_profile(&profile);
+
+    apply(pool, format!("Profile {}", profile), &old, &new).await
+}
+
+/// Puts the config replaced by the last reload back and applies it
+pub async fn rollback_config(pool: &PgPool) -> anyhow::Result<ReloadSummary> {
+    let old = crate::config::read_current_config().unwrap_or_else(|_| json!({}));
+    let new = crate::config::rollback_config().await?;
+
+    apply(pool, "Previous config".to_string(), &old, &new).await
+}
+
+async fn apply(
+    pool: &PgPool,
+    source: String,
+    old: &Value,
+    new: &Value,
+) -> anyhow::Result<ReloadSummary> {
+    let mut summary = ReloadSummary {
+        source,
+        diff: diff(old, new),
+        xray_running: super::is_xray_running(),
+        restarted: None,
+        resynced: None,
+    };
+
+    if summary.diff.is_empty() || !summary.xray_running {
+        return Ok(summary);
+    }
+
+    if summary.diff.needs_restart() {
+        summary.restarted = Some(super::restart_xray(pool).await?);
+    } else {
+        summary.resynced = apply_live(pool, &summary.diff).await
+            .context("Config was only partially applied, run `necko-xray restart`")?;
+    }
+
+    Ok(summary)
+}
+
+async fn apply_live(
+    pool: &PgPool,
+    diff: &ConfigDiff,
+) -> anyhow::Result<Option<SyncSummary>> {
+    let config = crate::config::current_config_protobuf().await?;
+    let client = Client::connect().await?;
+    let mut handler = client.handler();
+
+    for tag in &diff.inbounds_removed {
+        handler.remove_inbound(RemoveInboundRequest { tag: tag.clone() }).await?;
+    }
+
+    for tag in &diff.outbounds_removed {
+        handler.remove_outbound(RemoveOutboundRequest { tag: tag.clone() }).await?;
+    }
+
+    for tag in &diff.outbounds_added {
+        let outbound = config.outbound
+            .iter()
+            .find(|o| &o.tag == tag)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Outbound {} missing in converted config", tag))?;
+
+        handler.add_outbound(AddOutboundRequest { outbound: Some(outbound) }).await?;
+    }
+
+    if diff.routing_changed {
+        let bans = crate::data::postgres::get_active_ip_bans(pool).await?;
+        let router = super::bans::router_with_bans(&config, &bans)?;
+
+        // shouldAppend = false replaces all rules and balancers at once
+        client.routing()
+            .add_rule(AddRuleRequest { config: Some(router), should_append: false })
+            .await?;
+    }
+
+    for tag in &diff.inbounds_added {
+        let inbound = config.inbound
+            .iter()
+            .find(|i| &i.tag == tag)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Inbound {} missing in converted config", tag))?;
+
+        handler.add_inbound(AddInboundRequest { inbound: Some(inbound) }).await?;
+    }
+
+    if diff.inbounds_added.is_empty() {
+        return Ok(None);
+    }
+
+    let sync = super::sync::sync_inbounds(pool, &client, &diff.inbounds_added).await?;
+
+    Ok(Some(sync))
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::process::Child;
+use uuid::Uuid;
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Give up after this many crashes within `CRASH_WINDOW`
+const CRASH_LOOP_LIMIT: usize = 5;
+const CRASH_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct XrayStatus {
+    pub running: bool,
+    pub pid: Option<u32>,
+    pub started_at: Option<DateTime<Utc>>,
+    /// Automatic restarts since the daemon started
+    pub restart_count: u64,
+    pub last_exit_status: Option<String>,
+    pub last_exit_at: Option<DateTime<Utc>>,
+    /// Supervisor gave up restarting, `necko-xray start` resets it
+    pub crash_looping: bool,
+}
+
+#[derive(Default)]
+struct SupervisorState {
+    status: XrayStatus,
+    /// false once Xray is stopped on purpose
+    wanted: bool,
+    /// Bumped on every manual start so a supervisor left sleeping in backoff
+    /// does not spawn a second Xray next to the new one
+    generation: u64,
+    /// Identifies the running Xray process; its stat counters start at zero
+    /// with every new one
+    epoch: Option<Uuid>,
+    crashes: VecDeque<Instant>,
+}
+
+lazy_static!(
+    static ref STATE: Mutex<SupervisorState> = Mutex::new(SupervisorState::default());
+);
+
+pub fn status() -> XrayStatus {
+    let state = STATE.lock().unwrap();
+
+    let mut status = state.status.clone();
+    status.running = super::is_xray_running();
+    if !status.running {
+        status.pid = None;
+    }
+
+    status
+}
+
+/// Called for manual starts; clears a previous crash loop.
+/// Returns the generation the new supervisor runs under
+pub(super) fn want_running() -> u64 {
+    let mut state = STATE.lock().unwrap();
+    state.wanted = true;
+    state.generation += 1;
+    state.crashes.clear();
+    state.status.crash_looping = false;
+
+    state.generation
+}
+
+/// Marks the next exit as intentional, also cancels a pending restart
+pub(super) fn want_stopped() {
+    STATE.lock().unwrap().wanted = false;
+}
+
+fn is_wanted(generation: u64) -> bool {
+    let state = STATE.lock().unwrap();
+    state.wanted && state.generation == generation
+}
+
+pub(super) fn epoch() -> Option<Uuid> {
+    STATE.lock().unwrap().epoch
+}
+
+/// The epoch is kept next to Xray's PID file, so a daemon that crashed
+/// while Xray kept running can still count what that process served
+pub(super) fn epoch_path() -> String {
+    format!("{}.epoch", crate::settings::get().xray.pid_file)
+}
+
+/// Takes over the epoch of an Xray started by a previous daemon
+pub(super) fn adopt_epoch() -> Option<Uuid> {
+    let epoch = std::fs::read_to_string(epoch_path()).ok()?.trim().parse().ok()?;
+    STATE.lock().unwrap().epoch = Some(epoch);
+
+    Some(epoch)
+}
+
+pub(super) fn mark_started(pid: u32) {
+    let mut state = STATE.lock().unwrap();
+    let epoch = Uuid::new_v4();
+    if let Err(e) = std::fs::write(epoch_path(), epoch.to_string()) {
+        eprintln!("[necko-xray]: Failed to save the Xray epoch: {}", e);
+    }
+    state.epoch = Some(epoch);
+    state.status.pid = Some(pid);
+    state.status.started_at = Some(Utc::now());
+}
+
+/// Records the exit and tells whether it was unexpected.
+/// The PID file is removed only after `wanted` is read, so a restart that
+/// waits for `is_xray_running() == false` cannot race with this check.
+fn mark_exited(exit: &str, generation: u64) -> bool {
+    let mut state = STATE.lock().unwrap();
+    state.status.last_exit_status = Some(exit.to_string());
+    state.status.last_exit_at = Some(Utc::now());
+    state.status.pid = None;
+    state.epoch = None;
+
+    let unexpected = state.wanted && state.generation == generation;
+    let _ = std::fs::remove_file(epoch_path());
+    let _ = std::fs::remove_file(&crate::settings::get().xray.pid_file);
+
+    unexpected
+}
+
+/// Delay before the next restart, `None` when Xray is crash looping
+fn next_backoff() -> Option<Duration> {
+    let mut state = STATE.lock().unwrap();
+
+    let now = Instant::now();
+    while state.crashes.front().is_some_and(|t| now.duration_since(*t) > CRASH_WINDOW) {
+        state.crashes.pop_front();
+    }
+    state.crashes.push_back(now);
+
+    if state.crashes.len() > CRASH_LOOP_LIMIT {
+        state.status.crash_looping = true;
+        state.wanted = false;
+        return None;
+    }
+
+    let exp = state.crashes.len().saturating_sub(1) as u32;
+    Some(BACKOFF_BASE.saturating_mul(2u32.saturating_pow(exp)).min(BACKOFF_MAX))
+}
+
+fn describe(exit: std::io::Result<ExitStatus>) -> String {
+    match exit {
+        Ok(status) => status.to_string(),
+        Err(e) => format!("failed to wait: {}", e),
+    }
+}
+
+/// Owns the Xray child: waits for it and restarts it with exponential
+/// backoff unless the exit was requested through `want_stopped`
+pub(super) async fn supervise(mut child: Child, pool: PgPool, generation: u64) {
+    loop {
+        let exit = describe(child.wait().await);
+        println!("[necko-xray]: Xray exited with status: {}", exit);
+
+        if !mark_exited(&exit, generation) {
+            return;
+        }
+
+        loop {
+            let Some(delay) = next_backoff() else {
+                eprintln!("[necko-xray]: Xray is crash looping, giving up. \
+                    Run `necko-xray start` once the problem is fixed");
+                return;
+            };
+
+            println!("[necko-xray]: Restarting Xray in {}s...", delay.as_secs());
+            tokio::time::sleep(delay).await;
+
+            if !is_wanted(generation) {
+                return;
+            }
+
+            match super::spawn_xray() {
+                Ok(new_child) => {
+                    child = new_child;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("[necko-xray]: Failed to restart Xray: {}", e);
+                    mark_exited(&e.to_string(), generation);
+                }
+            }
+        }
+
+        STATE.lock().unwrap().status.restart_count += 1;
+
+        match super::sync::sync_users(&pool).await {
+            Ok(summary) => println!("[necko-xray]: {}", summary),
+            Err(e) => eprintln!("[necko-xray]: Failed to sync users: {}", e),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::time::Duration;
+use sqlx::PgPool;
+use crate::data::postgres::types::User;
+use crate::Client;
+
+const API_WAIT_ATTEMPTS: u32 = 50;
+const API_WAIT_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub users: usize,
+    pub added: usize,
+    /// (email, inbound tag, error)
+    pub failed: Vec<(String, String, String)>,
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Synced {} users into Xray: {} added, {} failed",
+               self.users, self.added, self.failed.len())?;
+
+        for (email, tag, error) in &self.failed {
+            write!(f, "\n  {} -> {}: {}", email, tag, error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits until the freshly started Xray answers on its API port
+pub async fn wait_for_api() -> anyhow::Result<Client> {
+    let mut last_error = anyhow::anyhow!("Xray API did not respond");
+
+    for _ in 0..API_WAIT_ATTEMPTS {
+        if !super::is_xray_running() {
+            anyhow::bail!("Xray exited before its API came up");
+        }
+
+        match Client::connect().await {
+            Ok(client) => match client.system_stats().await {
+                Ok(_) => return Ok(client),
+                Err(e) => last_error = e,
+            },
+            Err(e) => last_error = e,
+        }
+
+        tokio::time::sleep(API_WAIT_INTERVAL).await;
+    }
+
+    Err(last_error.context("Timed out waiting for Xray API"))
+}
+
+/// Replays every active user from the database into their inbounds, and
+/// the IP bans into the routing rules.
+/// Xray keeps runtime-added users in memory only, so this has to run after
+/// every (re)start of the core.
+pub async fn sync_users(pool: &PgPool) -> anyhow::Result<SyncSummary> {
+    let client = wait_for_api().await?;
+    let users = crate::data::postgres::get_active_users(pool).await?;
+
+    let summary = push_users(&client, users, None).await;
+
+    if let Err(e) = super::bans::restore(pool, &client).await {
+        eprintln!("[necko-xray]: Failed to restore IP bans: {:#}", e);
+    }
+
+    if let Err(e) = super::rebuild::restore(pool, &client, None).await {
+        eprintln!("[necko-xray]: Failed to put users into WireGuard, SOCKS or HTTP inbounds: {:#}", e);
+    }
+
+    Ok(summary)
+}
+
+/// Same as `sync_users` but only for the given inbounds, used after those
+/// inbounds were recreated on a running core
+pub async fn sync_inbounds(
+    pool: &PgPool,
+    client: &Client,
+    tags: &[String],
+) -> anyhow::Result<SyncSummary> {
+    let users = crate::data::postgres::get_active_users(pool).await?;
+    let summary = push_users(client, users, Some(tags)).await;
+
+    super::rebuild::restore(pool, client, Some(tags)).await?;
+
+    Ok(summary)
+}
+
+async fn push_users(
+    client: &Client,
+    users: Vec<User>,
+    only: Option<&[String]>,
+) -> SyncSummary {
+    let mut summary = SyncSummary::default();
+    let inbounds = crate::config::live_inbounds();
+
+    for user in users {
+        let tags: Vec<String> = user.inbounds
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|t| only.is_none_or(|only| only.contains(t)))
+            .collect();
+
+        if tags.is_empty() {
+            continue;
+        }
+        summary.users += 1;
+
+        for tag in tags {
+            match super::accounts::add(client, &inbounds, &user, &tag).await {
+                Ok(()) => summary.added += 1,
+                Err(e) => summary.failed.push(
+                    (user.email.clone(), tag, e.to_string())),
+            }
+        }
+    }
+
+    summary
+}
@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use prost::Message;
+use sqlx::PgPool;
+use crate::data::postgres::types::{User, WireguardPeer};
+use crate::proto::common::serial::{self, TypedMessage};
+use crate::proto::proxy::wireguard::{DeviceConfig, PeerConfig};
+
+/// Releases the peers of users that left a WireGuard inbound and gives
+/// every user on one of `tags` a peer. Runs from `rebuild::refresh`
+pub(super) async fn allocate(pool: &PgPool, tags: &[&String]) -> anyhow::Result<()> {
+    crate::data::postgres::release_stale_peers(pool).await?;
+
+    for tag in tags {
+        allocate_inbound(pool, tag).await?;
+    }
+
+    Ok(())
+}
+
+/// (public key, address) of the peers Xray should accept on the inbound
+pub(super) async fn enabled_peers(pool: &PgPool, tag: &str) -> anyhow::Result<Vec<(String, String)>> {
+    Ok(crate::data::postgres::get_enabled_peers(pool, tag)
+        .await?
+        .into_iter()
+        .map(|p| (p.public_key, p.address))
+        .collect())
+}
+
+/// Device settings with `peers` (public key, address) after those of the
+/// profile. Xray cannot add peers to a running inbound, so it is recreated
+/// with these
+pub(super) fn with_peers(
+    settings: &TypedMessage,
+    peers: &[(String, String)],
+) -> anyhow::Result<TypedMessage> {
+    let mut device = DeviceConfig::decode(settings.value.as_slice())?;
+
+    device.peers.extend(peers.iter().map(|(public_key, address)| PeerConfig {
+        public_key: public_key.clone(),
+        allowed_ips: vec![format!("{}/32", address)],
+        ..Default::default()
+    }));
+
+    Ok(serial::to_typed_message(&device, &settings.r#type))
+}
+
+/// Creates the missing peers of one inbound, each with a new keypair and
+/// the lowest free address of the pool
+async fn allocate_inbound(pool: &PgPool, tag: &str) -> anyhow::Result<()> {
+    let users = crate::data::postgres::get_users_without_peer(pool, tag).await?;
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    let address_pool = &crate::settings::get().wireguard.address_pool;
+    let mut taken: HashSet<Ipv4Addr> = crate::data::postgres::get_peer_addresses(pool, tag)
+        .await?
+        .iter()
+        .filter_map(|a| a.parse().ok())
+        .collect();
+
+    for user_id in users {
+        let address = next_free_address(address_pool, &taken)
+            .map_err(|e| anyhow::anyhow!("Inbound {}: {}", tag, e))?;
+        let (private_key, public_key) = generate_keypair().await?;
+
+        crate::data::postgres::create_peer(
+            pool, user_id, tag, &address.to_string(), &private_key, &public_key).await?;
+        taken.insert(address);
+    }
+
+    Ok(())
+}
+
+/// `a.b.c.d/prefix` as (network, prefix)
+fn parse_pool(pool: &str) -> anyhow::Result<(u32, u32)> {
+    let invalid = || anyhow::anyhow!("Invalid WireGuard address pool `{}`, expected e.g. 10.66.0.0/16", pool);
+
+    let (ip, prefix) = pool.split_once('/').ok_or_else(invalid)?;
+    let ip: Ipv4Addr = ip.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    if prefix > 30 {
+        return Err(invalid());
+    }
+
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Ok((u32::from(ip) & mask, prefix))
+}
+
+/// Lowest host of the pool not in `taken`. The network address, the first
+/// host (the server) and the broadcast address are never handed out
+fn next_free_address(pool: &str, taken: &HashSet<Ipv4Addr>) -> anyhow::Result<Ipv4Addr> {
+    let (network, prefix) = parse_pool(pool)?;
+    let size = 1u64 << (32 - prefix);
+
+    (2..size - 1)
+        .map(|offset| Ipv4Addr::from(network + offset as u32))
+        .find(|address| !taken.contains(address))
+        .ok_or_else(|| anyhow::anyhow!("WireGuard address pool {} is exhausted", pool))
+}
+
+/// New (private key, public key), base64 as wg-quick uses them
+async fn generate_keypair() -> anyhow::Result<(String, String)> {
+    let output = run_xray_wg(&[]).await?;
+
+    match (output_value(&output, "PrivateKey"), output_value(&output, "PublicKey")) {
+        (Some(private_key), Some(public_key)) => Ok((private_key, public_key)),
+        _ => anyhow::bail!("Unexpected `xray wg` output: {}", output),
+    }
+}
+
+async fn public_key(private_key: &str) -> anyhow::Result<String> {
+    let output = run_xray_wg(&["-i", private_key]).await?;
+
+    output_value(&output, "PublicKey")
+        .ok_or_else(|| anyhow::anyhow!("Unexpected `xray wg` output: {}", output))
+}
+
+/// Xray already does X25519, so keys come from `xray wg`
+async fn run_xray_wg(args: &[&str]) -> anyhow::Result<String> {
+    let bin = &crate::settings::get().xray.bin;
+
+    let output = tokio::process::Command::new(bin)
+        .arg("wg")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {} wg: {}", bin, e))?;
+
+    if !output.status.success() {
+        anyhow::bail!("xray wg failed: {}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Value of a `Name: value` line, also matching `Name value` spelled apart
+fn output_value(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.replace(' ', "").eq_ignore_ascii_case(name))
+            .then(|| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// wg-quick config for the user's peer on `tag`, which may be left out
+/// when the user is on a single WireGuard inbound
+pub async fn client_config(
+    pool: &PgPool,
+    user: &User,
+    tag: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut peers = crate::data::postgres::get_user_peers(pool, user.id).await?;
+    if peers.is_empty() {
+        // the user may have joined an inbound since the last refresh
+        super::rebuild::refresh(pool).await?;
+        peers = crate::data::postgres::get_user_peers(pool, user.id).await?;
+    }
+
+    let peer: WireguardPeer = match tag {
+        Some(tag) => peers.into_iter().find(|p| p.inbound_tag == tag).ok_or_else(|| {
+            crate::api::ApiError::not_found(format!(
+                "User {} has no WireGuard peer on {}", user.email, tag))
+        })?,
+        None if peers.len() == 1 => peers.remove(0),
+        None if peers.is_empty() => return Err(crate::api::ApiError::not_found(format!(
+            "User {} is not on a WireGuard inbound", user.email)).into()),
+        None => return Err(crate::api::ApiError::bad_request(format!(
+            "User {} is on several WireGuard inbounds, pick one with --inbound: {}",
+            user.email,
+            peers.iter().map(|p| p.inbound_tag.as_str()).collect::<Vec<_>>().join(", "))).into()),
+    };
+
+    let inbounds = crate::config::live_inbounds();
+    let inbound = inbounds.get(&peer.inbound_tag)
+        .ok_or_else(|| anyhow::anyhow!("Inbound {} is not in the active config", peer.inbound_tag))?;
+    let secret_key = inbound.settings["secretKey"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Inbound {} has no secretKey", peer.inbound_tag))?;
+    let port = inbound.port
+        .ok_or_else(|| anyhow::anyhow!("Inbound {} has no single port", peer.inbound_tag))?;
+
+    let settings = &crate::settings::get().wireguard;
+    let host = settings.endpoint.as_deref().ok_or_else(|| anyhow::anyhow!(
+        "Set wireguard.endpoint (NECKO_WIREGUARD_ENDPOINT) to the server's public host"))?;
+
+    let mut config = format!("[Interface]\nPrivateKey = {}\nAddress = {}/32\n",
+                             peer.private_key, peer.address);
+    if let Some(dns) = &settings.dns {
+        config += &format!("DNS = {}\n", dns);
+    }
+    if let Some(mtu) = inbound.settings["mtu"].as_u64() {
+        config += &format!("MTU = {}\n", mtu);
+    }
+    config += &format!("\n[Peer]\nPublicKey = {}\nAllowedIPs = 0.0.0.0/0, ::/0\nEndpoint = {}:{}\n",
+                       public_key(secret_key).await?, host, port);
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_free_address_test() {
+        let mut taken = HashSet::new();
+        assert_eq!(next_free_address("10.66.1.7/24", &taken).unwrap(), Ipv4Addr::new(10, 66, 1, 2));
+
+        taken.insert(Ipv4Addr::new(10, 66, 1, 2));
+        taken.insert(Ipv4Addr::new(10, 66, 1, 4));
+        assert_eq!(next_free_address("10.66.1.0/24", &taken).unwrap(), Ipv4Addr::new(10, 66, 1, 3));
+
+        taken.insert(Ipv4Addr::new(10, 66, 1, 3));
+        assert!(next_free_address("10.66.1.0/29", &taken).is_ok());
+        taken.extend([5, 6].map(|host| Ipv4Addr::new(10, 66, 1, host)));
+        assert!(next_free_address("10.66.1.0/29", &taken).is_err());
+
+        assert!(next_free_address("10.66.1.0/31", &taken).is_err());
+        assert!(next_free_address("10.66.1.0", &taken).is_err());
+    }
+
+    #[test]
+    fn output_value_test() {
+        let output = "PrivateKey: cGFzc3dvcmQ=\nPublic key: a2V5\n";
+
+        assert_eq!(output_value(output, "PrivateKey").unwrap(), "cGFzc3dvcmQ=");
+        assert_eq!(output_value(output, "PublicKey").unwrap(), "a2V5");
+        assert!(output_value(output, "Password").is_none());
+    }
+}
@@ -1,5 +1,8 @@
-use anyhow::bail;
-use crate::data::postgres::types::{CreateUser, IpLimitPunishment};
+use crate::api::daemon::logs::LogFilter;
+use crate::data::postgres::types::{
+    CreateUser, IpLimitPunishment, StatKind, TrafficBucketSize, UserListFilter, VmessSecurity,
+};
+use crate::data::postgres::TrafficResetTarget;
 use crate::proto::app::stats::command::SysStatsResponseSerializable;
 use crate::Client;
 use chrono::{DateTime, Utc};
@@ -7,15 +10,27 @@ use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
 pub mod daemon;
+mod response;
+
+pub use response::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     StartXray,
     StopXray,
     RestartXray,
+    XrayStatus,
+    /// Regenerates the config and applies it to the running Xray,
+    /// `None` reloads the active profile
+    ReloadProfile { profile: Option<String> },
+    /// Streams captured Xray output, handled by the socket server itself
+    Logs(LogFilter),
 
     GetStatsUserOnlineCount { email: String },
     GetStatsUserOnlineIpList { email: String },
+    /// Counters of the running Xray, which grow from its start since the
+    /// accounting reads them without resetting; stored totals are in
+    /// `users.traffic_used`
     GetStatsUserTraffic { email: String },
     GetStatsInboundTraffic { tag: String },
     GetStatsOutboundTraffic { tag: String },
@@ -32,6 +47,7 @@ pub enum Request {
         ip_limit_punishment: Option<IpLimitPunishment>,
         ip_expire_after: i64,
         is_active: bool,
+        vmess_security: VmessSecurity,
     },
     UpdateUser {
         email: String,
@@ -44,39 +60,107 @@ pub enum Request {
         ip_limit_punishment: Option<IpLimitPunishment>,
         ip_expire_after: Option<i64>,
         is_active: Option<bool>,
+        vmess_security: Option<VmessSecurity>,
     },
     DeleteUser { email: String },
     GetAllUsers,
+
+    /// Swaps the live Xray config with the one replaced by the last reload
+    RollbackConfig,
+    /// Zeroes traffic_used of one user or of every user with a tag
+    ResetTraffic { email: Option<String>, tag: Option<String> },
+    /// Moves expire_at forward, from now if it already passed
+    ExtendUser { email: String, seconds: i64 },
+    /// Connection history of a user, newest first
+    GetUserSessions { email: String, since: Option<DateTime<Utc>> },
+    /// Stored traffic of a user, inbound or outbound per hour, day or month
+    GetTrafficHistory {
+        kind: StatKind,
+        name: String,
+        bucket: TrafficBucketSize,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    },
+    /// Full record of one user with live Xray data
+    GetUser { email: String },
+    /// Streams matching users, handled by the socket server itself
+    ListUsers(UserListFilter),
+    /// Gives the user a new Shadowsocks 2022 key on every inbound
+    RotateShadowsocksKey { email: String },
+    /// wg-quick config of the user's peer, `inbound` picks one of several
+    GetWireguardConfig { email: String, inbound: Option<String> },
 }
 
-pub async fn handle_command(pool: PgPool, request: Request) -> anyhow::Result<String> {
+impl Request {
+    /// Every kind the daemon handles, advertised in the handshake
+    pub const KINDS: &'static [&'static str] = &[
+        "StartXray", "StopXray", "RestartXray", "XrayStatus", "ReloadProfile", "Logs",
+        "GetStatsUserOnlineCount", "GetStatsUserOnlineIpList", "GetStatsUserTraffic",
+        "GetStatsInboundTraffic", "GetStatsOutboundTraffic", "GetStatsSystem",
+        "CreateUser", "UpdateUser", "DeleteUser", "GetAllUsers",
+        "RollbackConfig", "ResetTraffic", "ExtendUser", "GetUserSessions",
+        "GetTrafficHistory", "GetUser", "ListUsers",
+        "RotateShadowsocksKey", "GetWireguardConfig",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::StartXray => "StartXray",
+            Request::StopXray => "StopXray",
+            Request::RestartXray => "RestartXray",
+            Request::XrayStatus => "XrayStatus",
+            Request::ReloadProfile { .. } => "ReloadProfile",
+            Request::Logs(_) => "Logs",
+            Request::GetStatsUserOnlineCount { .. } => "GetStatsUserOnlineCount",
+            Request::GetStatsUserOnlineIpList { .. } => "GetStatsUserOnlineIpList",
+            Request::GetStatsUserTraffic { .. } => "GetStatsUserTraffic",
+            Request::GetStatsInboundTraffic { .. } => "GetStatsInboundTraffic",
+            Request::GetStatsOutboundTraffic { .. } => "GetStatsOutboundTraffic",
+            Request::GetStatsSystem => "GetStatsSystem",
+            Request::CreateUser { .. } => "CreateUser",
+            Request::UpdateUser { .. } => "UpdateUser",
+            Request::DeleteUser { .. } => "DeleteUser",
+            Request::GetAllUsers => "GetAllUsers",
+            Request::RollbackConfig => "RollbackConfig",
+            Request::ResetTraffic { .. } => "ResetTraffic",
+            Request::ExtendUser { .. } => "ExtendUser",
+            Request::GetUserSessions { .. } => "GetUserSessions",
+            Request::GetTrafficHistory { .. } => "GetTrafficHistory",
+            Request::GetUser { .. } => "GetUser",
+            Request::ListUsers(_) => "ListUsers",
+            Request::RotateShadowsocksKey { .. } => "RotateShadowsocksKey",
+            Request::GetWireguardConfig { .. } => "GetWireguardConfig",
+        }
+    }
+}
+
+pub async fn handle_command(pool: PgPool, request: Request) -> anyhow::Result<Response> {
     let req = request.clone();
     match request {
         Request::StartXray => {
-            daemon::start_xray().await?;
-            Ok("Xray started".into())
+            let summary = daemon::start_xray(&pool).await?;
+            Ok(Response::Message(format!("Xray started\n{}", summary)))
         }
         Request::StopXray => {
+            daemon::save_traffic(&pool).await;
             daemon::stop().await?;
-            Ok("Xray stopped".into())
+            Ok(Response::Message("Xray stopped".into()))
         }
         Request::RestartXray => {
-            daemon::stop().await?;
-
-            for _ in 0..50 {
-                if !daemon::is_xray_running() {
-                    break;
-                }
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            }
-
-            if daemon::is_xray_running() {
-                bail!("Failed to stop Xray process");
-            }
-
-            daemon::start_xray().await?;
-            Ok("Xray restarted".into())
+            let summary = daemon::restart_xray(&pool).await?;
+            Ok(Response::Message(format!("Xray restarted\n{}", summary)))
+        }
+        Request::ReloadProfile { profile } => {
+            let summary = daemon::reload::reload_profile(&pool, profile).await?;
+            Ok(Response::Message(summary.to_string()))
+        }
+        Request::RollbackConfig => {
+            let summary = daemon::reload::rollback_config(&pool).await?;
+            Ok(Response::Message(summary.to_string()))
         }
+        Request::Logs(_) => Err(ApiError::bad_request("Logs can only be streamed").into()),
+        Request::ListUsers(_) => Err(ApiError::bad_request("User lists can only be streamed").into()),
+        Request::XrayStatus => Ok(Response::XrayStatus(daemon::supervisor::status())),
 
         Request::GetStatsUserOnlineCount { email } =>
             get_stats_user_online_count(&email).await,
@@ -90,14 +174,25 @@ pub async fn handle_command(pool: PgPool, request: Request) -> anyhow::Result<St
             get_stats_outbound_traffic(&tag).await,
         Request::GetStatsSystem =>
             get_stats_system().await,
+        Request::GetTrafficHistory { kind, name, bucket, since, until } => {
+            if let (Some(since), Some(until)) = (since, until)
+                && since >= until {
+                return Err(ApiError::bad_request("--since must be before --until").into());
+            }
+
+            let history = crate::data::postgres::get_traffic_history(
+                &pool, kind, &name, bucket, since, until).await?;
+
+            Ok(Response::TrafficHistory(history))
+        }
 
         Request::CreateUser { email, tags, inbounds,
             traffic_limit, reset_traffic_every, expire_at,
             ip_limit, ip_limit_punishment, ip_expire_after,
-            is_active
+            is_active, vmess_security
         } => {
             let ip_limit_punishment = ip_limit_punishment
-                .map(|ilp| sqlx::types::Json(ilp));
+                .map(sqlx::types::Json);
 
             let data = CreateUser {
                 email,
@@ -110,147 +205,268 @@ pub async fn handle_command(pool: PgPool, request: Request) -> anyhow::Result<St
                 ip_limit_punishment,
                 ip_expire_after,
                 is_active,
+                vmess_security,
             };
 
             let user = crate::data::postgres::create_user(&pool, data).await?;
 
-            create_user(user).await?;
+            create_user(user.clone()).await?;
+            daemon::rebuild::refresh(&pool).await?;
+
+            // takes an already expired or over-quota user back out
+            daemon::enforce::reconcile(&pool).await?;
+            daemon::enforce::expiry_changed();
 
-            Ok("User created".to_string())
+            Ok(Response::User(Box::new(user)))
         }
         Request::UpdateUser { email, ..} => {
-            let user = crate::data::postgres::get_user_by_email(
-                &pool, &email).await?.unwrap();
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
 
-            let old_inbounds = user.inbounds.unwrap_or(vec![]);
+            // a disabled user is not in Xray, reconcile adds them if the update lifted that
+            let was_enabled = user.is_active && user.disabled_reason.is_none();
 
-            let user = crate::data::postgres::update_user(
+            let updated = crate::data::postgres::update_user(
                 &pool, req).await?;
+            let enabled = updated.is_active && updated.disabled_reason.is_none();
 
-            let client = Client::connect().await?;
+            // reconcile only follows disabled_reason, is_active is applied here
+            if daemon::is_xray_running() {
+                let client = Client::connect().await?;
+
+                match (was_enabled, enabled) {
+                    (true, true) => daemon::accounts::sync(&client, &user, &updated).await?,
+                    (true, false) => daemon::enforce::disable(&client, &user).await?,
+                    (false, true) => daemon::enforce::enable(&client, &updated).await?,
+                    (false, false) => {}
+                }
+            }
 
-            client.sync_user_inbounds(&user.email,
-                                      &user.id.to_string(),
-                                      old_inbounds,
-                                      user.inbounds.unwrap_or(vec![])).await?;
+            daemon::enforce::reconcile(&pool).await?;
+            daemon::enforce::expiry_changed();
+            daemon::rebuild::refresh(&pool).await?;
 
-            Ok("User updated".to_string())
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            Ok(Response::User(Box::new(user)))
         }
         Request::DeleteUser { email } => {
-            let user = crate::data::postgres::get_user_by_email(
-                &pool, &email).await?;
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            // the traffic used until now still goes into the history
+            daemon::save_traffic(&pool).await;
+            crate::data::postgres::delete_user_by_id(&pool, user.id).await?;
+
+            let removed = remove_user(user).await;
+            // the row is gone either way, so are their peer and SOCKS/HTTP accounts
+            daemon::rebuild::refresh(&pool).await?;
+            removed?;
+
+            Ok(Response::Message(format!("User {} deleted", email)))
+        }
+        Request::ResetTraffic { email, tag } => {
+            let target = match (email, tag) {
+                (Some(email), None) => TrafficResetTarget::Email(email),
+                (None, Some(tag)) => TrafficResetTarget::Tag(tag),
+                _ => return Err(ApiError::bad_request("Give either an email or a tag").into()),
+            };
+            let not_found = match &target {
+                TrafficResetTarget::Email(email) => format!("User {} not found", email),
+                _ => "No users with this tag".to_string(),
+            };
+
+            // what was used until now still counts for the period that ends
+            daemon::save_traffic(&pool).await;
+            let emails = daemon::accounting::reset_traffic(&pool, target).await?;
+            if emails.is_empty() {
+                return Err(ApiError::not_found(not_found).into());
+            }
 
-            if user.is_none() {
-                bail!("User {} not found", email);
+            Ok(Response::Message(format!("Traffic reset for {} users: {}",
+                                         emails.len(), emails.join(", "))))
+        }
+        Request::ExtendUser { email, seconds } => {
+            if seconds <= 0 {
+                return Err(ApiError::bad_request("Extend by a positive duration").into());
             }
 
-            let user = user.unwrap();
+            if crate::data::postgres::extend_user(&pool, &email, seconds).await?.is_none() {
+                return match crate::data::postgres::get_user_by_email(&pool, &email).await? {
+                    Some(_) => Err(ApiError::bad_request(format!(
+                        "User {} never expires, set a date with --expire-at", email)).into()),
+                    None => Err(ApiError::not_found(format!("User {} not found", email)).into()),
+                };
+            }
 
-            let successful = crate::data::postgres::delete_user_by_id(
-                &pool, user.id).await?;
+            daemon::enforce::reconcile(&pool).await?;
+            daemon::enforce::expiry_changed();
 
-            remove_user(user).await?;
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            Ok(Response::User(Box::new(user)))
+        }
+        Request::RotateShadowsocksKey { email } => {
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            let rotated = crate::data::postgres::rotate_ss_key(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            if user.is_active && user.disabled_reason.is_none() && daemon::is_xray_running() {
+                let client = Client::connect().await?;
+                daemon::accounts::sync(&client, &user, &rotated).await?;
+            }
+
+            let shadowsocks_keys = daemon::accounts::shadowsocks_keys(&rotated);
+            let mut details = UserDetails::new(rotated, Utc::now());
+            details.shadowsocks_keys = shadowsocks_keys;
+
+            Ok(Response::UserDetails(Box::new(details)))
+        }
+        Request::GetWireguardConfig { email, inbound } => {
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            let config = daemon::wireguard::client_config(&pool, &user, inbound.as_deref()).await?;
+
+            Ok(Response::Message(config))
+        }
+        Request::GetUserSessions { email, since } => {
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            let sessions = crate::data::postgres::get_user_sessions(
+                &pool, user.id, since).await?;
+
+            Ok(Response::Sessions(sessions))
+        }
+        Request::GetUser { email } => {
+            let user = crate::data::postgres::get_user_by_email(&pool, &email)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("User {} not found", email)))?;
+
+            let in_xray = user.is_active && user.disabled_reason.is_none()
+                && daemon::is_xray_running();
+            let shadowsocks_keys = daemon::accounts::shadowsocks_keys(&user);
+            let mut details = UserDetails::new(user, Utc::now());
+            details.shadowsocks_keys = shadowsocks_keys;
+
+            // live data is best effort, the stored record is what was asked for
+            if in_xray && let Ok(client) = Client::connect().await {
+                details.online_ip_count = client.user_online_count(&email).await.ok();
+                if let Ok((uplink, downlink)) = client.user_traffic(&email).await {
+                    details.xray_uplink = Some(uplink);
+                    details.xray_downlink = Some(downlink);
+                }
+            }
 
-            Ok(successful.to_string())
+            Ok(Response::UserDetails(Box::new(details)))
         }
         Request::GetAllUsers => {
             let users = crate::data::postgres::get_all_user_emails(
                 &pool).await?;
 
-            let formatted = serde_json::to_string_pretty(&users)?;
-
-            Ok(formatted)
+            Ok(Response::Emails(users))
         }
     }
 }
 
-async fn get_stats_user_online_count(email: &str) -> anyhow::Result<String> {
+async fn get_stats_user_online_count(email: &str) -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
     let response = client.user_online_count(email).await?;
 
-    Ok(response.to_string())
+    Ok(Response::Count(response))
 }
 
-async fn get_stats_user_online_ip_list(email: &str) -> anyhow::Result<String> {
+async fn get_stats_user_online_ip_list(email: &str) -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
     let response = client.user_online_ip_list(email).await?;
 
-    let formatted = serde_json::to_string_pretty(&response)?;
-
-    Ok(formatted)
+    Ok(Response::IpList(response))
 }
 
-async fn get_stats_user_traffic(email: &str) -> anyhow::Result<String> {
+async fn get_stats_user_traffic(email: &str) -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
-    let response = client.user_traffic(email).await?;
-
-    let formatted = format!("{} {}", response.0, response.1);
+    let (uplink, downlink) = client.user_traffic(email).await?;
 
-    Ok(formatted)
+    Ok(Response::Traffic { uplink, downlink })
 }
 
-async fn get_stats_inbound_traffic(tag: &str) -> anyhow::Result<String> {
+async fn get_stats_inbound_traffic(tag: &str) -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
-    let response = client.inbound_traffic(tag).await?;
+    let (uplink, downlink) = client.inbound_traffic(tag).await?;
 
-    let formatted = format!("{} {}", response.0, response.1);
-
-    Ok(formatted)
+    Ok(Response::Traffic { uplink, downlink })
 }
 
-async fn get_stats_outbound_traffic(tag: &str) -> anyhow::Result<String> {
+async fn get_stats_outbound_traffic(tag: &str) -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
-    let response = client.outbound_traffic(tag).await?;
-
-    let formatted = format!("{} {}", response.0, response.1);
+    let (uplink, downlink) = client.outbound_traffic(tag).await?;
 
-    Ok(formatted)
+    Ok(Response::Traffic { uplink, downlink })
 }
 
-async fn get_stats_system() -> anyhow::Result<String> {
+async fn get_stats_system() -> anyhow::Result<Response> {
     let client = Client::connect().await?;
 
     let response = client.system_stats().await?;
 
-    let formatted = serde_json::to_string_pretty(
-        &SysStatsResponseSerializable::from(response))?;
-
-    Ok(formatted)
+    Ok(Response::SysStats(SysStatsResponseSerializable::from(response)))
 }
 
+/// Inactive users and a stopped Xray are left alone, `sync_users` adds
+/// the right users on start
 async fn create_user(
     user: crate::data::postgres::types::User
 ) -> anyhow::Result<()> {
-    let client = Client::connect().await?;
-
-    let id = user.id.to_string();
-    let email = user.email;
-
-    // todo there is not only vless exists
-    let tags = user.inbounds.unwrap_or(vec![]);
-    for tag in tags {
-        client.add_vless_user(&tag, &id, &email).await?;
+    if !user.is_active || !daemon::is_xray_running() {
+        return Ok(());
     }
 
-    Ok(())
+    let client = Client::connect().await?;
+
+    daemon::accounts::add_all(&client, &user).await
 }
 
+/// Only an enabled user is in the inbounds of a running Xray
 async fn remove_user(
     user: crate::data::postgres::types::User
 ) -> anyhow::Result<()> {
+    if !daemon::is_xray_running() {
+        return Ok(());
+    }
+
     let client = Client::connect().await?;
 
     let email = user.email;
+    let inbounds = crate::config::live_inbounds();
 
-    let tags = user.inbounds.unwrap_or(vec![]);
-    for tag in tags {
-        client.remove_vless_user(&tag, &email).await?;
+    if user.is_active && user.disabled_reason.is_none() {
+        let tags = user.inbounds.unwrap_or(vec![]);
+        for tag in tags {
+            daemon::accounts::remove(&client, &inbounds, &email, &tag).await?;
+        }
     }
 
+    // the database checkpoint went with the row, Xray's counters would
+    // otherwise be counted again for a new user with this email
+    client.reset_user_traffic(&email).await?;
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use crate::api::daemon::logs::LogLine;
+use crate::api::daemon::supervisor::XrayStatus;
+use crate::data::postgres::types::{TrafficBucket, User, UserSession};
+use crate::proto::app::stats::command::SysStatsResponseSerializable;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadRequest,
+    NotFound,
+    AlreadyExists,
+    /// Xray or the database cannot be reached
+    Unavailable,
+    Internal,
+    /// The client's uid/gid is not allowed to send this request
+    PermissionDenied,
+}
+
+impl ErrorCode {
+    /// Exit code of the CLI for this error
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::BadRequest => 2,
+            ErrorCode::NotFound => 3,
+            ErrorCode::AlreadyExists => 4,
+            ErrorCode::Unavailable => 5,
+            ErrorCode::PermissionDenied => 6,
+        }
+    }
+}
+
+/// Error with an explicit code, anything else returned by a handler is
+/// classified in `Response::from_error`
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Response {
+    Message(String),
+    Count(i64),
+    Traffic { uplink: i64, downlink: i64 },
+    IpList(HashMap<String, i64>),
+    SysStats(SysStatsResponseSerializable),
+    XrayStatus(XrayStatus),
+    User(Box<User>),
+    Emails(Vec<String>),
+    /// One of many frames sent by `Request::Logs`
+    LogLine(LogLine),
+    Error { code: ErrorCode, message: String },
+    Sessions(Vec<UserSession>),
+    TrafficHistory(Vec<TrafficBucket>),
+    UserDetails(Box<UserDetails>),
+    /// Last frame of `Request::ListUsers`, `next` continues the listing
+    UserListEnd { next: Option<String> },
+}
+
+/// A user row with what Xray currently reports and values derived from both
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserDetails {
+    pub user: User,
+    /// `None` while the user is not in a running Xray
+    pub online_ip_count: Option<i64>,
+    /// Counters of the running Xray process, not yet all in `traffic_used`
+    pub xray_uplink: Option<i64>,
+    pub xray_downlink: Option<i64>,
+    /// `None` without a traffic limit
+    pub remaining_traffic: Option<i64>,
+    /// Whole days left, negative once expired
+    pub days_to_expiry: Option<i64>,
+    pub next_traffic_reset_at: Option<DateTime<Utc>>,
+    /// User key on each Shadowsocks 2022 inbound by tag, for share links
+    pub shadowsocks_keys: BTreeMap<String, String>,
+}
+
+impl UserDetails {
+    pub fn new(user: User, now: DateTime<Utc>) -> Self {
+        let remaining_traffic = (user.traffic_limit > 0)
+            .then(|| (user.traffic_limit - user.traffic_used).max(0));
+        let days_to_expiry = user.expire_at.map(|at| (at - now).num_days());
+        let next_traffic_reset_at = user.reset_traffic_every
+            .filter(|every| *every > 0)
+            .map(|every| user.last_traffic_reset_at.unwrap_or(user.created_at)
+                + TimeDelta::seconds(every));
+
+        Self {
+            user,
+            online_ip_count: None,
+            xray_uplink: None,
+            xray_downlink: None,
+            remaining_traffic,
+            days_to_expiry,
+            next_traffic_reset_at,
+            shadowsocks_keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl Response {
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let code = if let Some(e) = error.downcast_ref::<ApiError>() {
+            e.code
+        } else if let Some(e) = error.downcast_ref::<sqlx::Error>() {
+            match e {
+                sqlx::Error::RowNotFound => ErrorCode::NotFound,
+                sqlx::Error::Database(db) if db.is_unique_violation() => ErrorCode::AlreadyExists,
+                sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => ErrorCode::Unavailable,
+                _ => ErrorCode::Internal,
+            }
+        } else if let Some(status) = error.downcast_ref::<tonic::Status>() {
+            match status.code() {
+                tonic::Code::Unavailable => ErrorCode::Unavailable,
+                tonic::Code::NotFound => ErrorCode::NotFound,
+                tonic::Code::InvalidArgument => ErrorCode::BadRequest,
+                _ => ErrorCode::Internal,
+            }
+        } else if error.downcast_ref::<tonic::transport::Error>().is_some() {
+            ErrorCode::Unavailable
+        } else {
+            ErrorCode::Internal
+        };
+
+        Response::Error { code, message: format!("{:#}", error) }
+    }
+}
+
+/// Text shown by the CLI; keeps the formats the string protocol used
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Message(message) => f.write_str(message),
+            Response::Count(count) => write!(f, "{}", count),
+            Response::Traffic { uplink, downlink } => write!(f, "{} {}", uplink, downlink),
+            Response::IpList(ips) => f.write_str(&pretty(ips)?),
+            Response::SysStats(stats) => f.write_str(&pretty(stats)?),
+            Response::XrayStatus(status) => f.write_str(&pretty(status)?),
+            Response::User(user) => f.write_str(&pretty(user)?),
+            Response::Emails(emails) => f.write_str(&pretty(emails)?),
+            Response::LogLine(line) => f.write_str(&line.text),
+            Response::Error { message, .. } => f.write_str(message),
+            Response::Sessions(sessions) => f.write_str(&pretty(sessions)?),
+            Response::TrafficHistory(history) => f.write_str(&pretty(history)?),
+            Response::UserDetails(details) => f.write_str(&pretty(details)?),
+            Response::UserListEnd { next: Some(next) } => write!(f, "More users: --after {}", next),
+            Response::UserListEnd { next: None } => Ok(()),
+        }
+    }
+}
+
+fn pretty<T: Serialize>(value: &T) -> Result<String, fmt::Error> {
+    serde_json::to_string_pretty(value).map_err(|_| fmt::Error)
+}
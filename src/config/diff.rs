@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashSet};
+use serde_json::Value;
+
+/// Top-level sections Xray can change at runtime through its API
+const LIVE_SECTIONS: &[&str] = &["inbounds", "outbounds", "routing"];
+
+/// The api inbound carries our own control connection, never touch it live
+const API_TAG: &str = "api";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    /// Tags to remove, includes changed handlers
+    pub inbounds_removed: Vec<String>,
+    /// Tags to add, includes changed handlers
+    pub inbounds_added: Vec<String>,
+    pub outbounds_removed: Vec<String>,
+    pub outbounds_added: Vec<String>,
+    /// Routing rules or balancers changed
+    pub routing_changed: bool,
+    /// Changed sections that need a full restart
+    pub restart_sections: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn needs_restart(&self) -> bool {
+        !self.restart_sections.is_empty()
+    }
+}
+
+pub fn diff(old: &Value, new: &Value) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+
+    let mut keys: Vec<&String> = section_keys(old)
+        .union(&section_keys(new))
+        .copied()
+        .collect();
+    keys.sort();
+
+    for key in keys {
+        if LIVE_SECTIONS.contains(&key.as_str()) || old.get(key) == new.get(key) {
+            continue;
+        }
+        diff.restart_sections.push(key.clone());
+    }
+
+    match diff_handlers(old.get("inbounds"), new.get("inbounds")) {
+        Some((removed, added))
+            if !removed.iter().chain(&added).any(|t| t == API_TAG) => {
+            diff.inbounds_removed = removed;
+            diff.inbounds_added = added;
+        }
+        _ => diff.restart_sections.push("inbounds".to_string()),
+    }
+
+    match diff_handlers(old.get("outbounds"), new.get("outbounds")) {
+        // removing the default outbound makes Xray promote whichever handler
+        // is added next, or leaves it without a default at all
+        Some(_) if first(old.get("outbounds")) != first(new.get("outbounds")) =>
+            diff.restart_sections.push("outbounds (default)".to_string()),
+        Some((removed, added)) => {
+            diff.outbounds_removed = removed;
+            diff.outbounds_added = added;
+        }
+        None => diff.restart_sections.push("outbounds".to_string()),
+    }
+
+    let old_routing = old.get("routing");
+    let new_routing = new.get("routing");
+    if field(old_routing, "domainStrategy") != field(new_routing, "domainStrategy") {
+        diff.restart_sections.push("routing.domainStrategy".to_string());
+    } else if field(old_routing, "rules") != field(new_routing, "rules")
+        || field(old_routing, "balancers") != field(new_routing, "balancers") {
+        diff.routing_changed = true;
+    }
+
+    diff
+}
+
+fn section_keys(config: &Value) -> HashSet<&String> {
+    config
+        .as_object()
+        .map(|o| o.keys().collect())
+        .unwrap_or_default()
+}
+
+fn first(handlers: Option<&Value>) -> Option<&Value> {
+    handlers.and_then(|h| h.get(0))
+}
+
+fn field<'a>(section: Option<&'a Value>, name: &str) -> Option<&'a Value> {
+    section.and_then(|s| s.get(name))
+}
+
+/// Returns (removed, added) handler tags, `None` if handlers without a tag
+/// changed since those cannot be addressed through the API
+fn diff_handlers(
+    old: Option<&Value>,
+    new: Option<&Value>,
+) -> Option<(Vec<String>, Vec<String>)> {
+    let (old_tagged, old_untagged) = split_by_tag(old);
+    let (new_tagged, new_untagged) = split_by_tag(new);
+
+    if old_untagged != new_untagged {
+        return None;
+    }
+
+    let removed = old_tagged
+        .iter()
+        .filter(|(tag, h)| new_tagged.get(*tag) != Some(*h))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+
+    let added = new_tagged
+        .iter()
+        .filter(|(tag, h)| old_tagged.get(*tag) != Some(*h))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+
+    Some((removed, added))
+}
+
+fn split_by_tag(handlers: Option<&Value>) -> (BTreeMap<String, &Value>, Vec<&Value>) {
+    let mut tagged = BTreeMap::new();
+    let mut untagged = vec![];
+
+    for handler in handlers.and_then(Value::as_array).into_iter().flatten() {
+        match handler.get("tag").and_then(Value::as_str) {
+            Some(tag) => { tagged.insert(tag.to_string(), handler); }
+            None => untagged.push(handler),
+        }
+    }
+
+    (tagged, untagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn base() -> Value {
+        json!({
+            "log": { "loglevel": "warning" },
+            "inbounds": [
+                { "tag": "api", "port": 10085 },
+                { "tag": "vless", "port": 443 }
+            ],
+            "outbounds": [
+                { "tag": "direct", "protocol": "freedom" }
+            ],
+            "routing": {
+                "rules": [ { "inboundTag": ["api"], "outboundTag": "api" } ]
+            }
+        })
+    }
+
+    #[test]
+    fn diff_test() {
+        assert!(diff(&base(), &base()).is_empty());
+
+        let mut new = base();
+        new["inbounds"][1]["port"] = json!(8443);
+        new["outbounds"].as_array_mut().unwrap()
+            .push(json!({ "tag": "block", "protocol": "blackhole" }));
+        new["routing"]["rules"].as_array_mut().unwrap()
+            .push(json!({ "protocol": "bittorrent", "outboundTag": "block" }));
+
+        let d = diff(&base(), &new);
+        assert_eq!(d.inbounds_removed, vec!["vless"]);
+        assert_eq!(d.inbounds_added, vec!["vless"]);
+        assert!(d.outbounds_removed.is_empty());
+        assert_eq!(d.outbounds_added, vec!["block"]);
+        assert!(d.routing_changed);
+        assert!(!d.needs_restart());
+
+        let mut new = base();
+        new["log"]["loglevel"] = json!("debug");
+        new["inbounds"][0]["port"] = json!(10086);
+        new["outbounds"].as_array_mut().unwrap()
+            .push(json!({ "protocol": "blackhole" }));
+
+        let d = diff(&base(), &new);
+        assert_eq!(d.restart_sections, vec!["log", "inbounds", "outbounds"]);
+    }
+
+    #[test]
+    fn default_outbound_test() {
+        let mut new = base();
+        new["outbounds"][0]["settings"] = json!({ "domainStrategy": "UseIPv4" });
+        assert_eq!(diff(&base(), &new).restart_sections, vec!["outbounds (default)"]);
+
+        let mut new = base();
+        new["outbounds"].as_array_mut().unwrap()
+            .insert(0, json!({ "tag": "proxy", "protocol": "vless" }));
+        let d = diff(&base(), &new);
+        assert_eq!(d.restart_sections, vec!["outbounds (default)"]);
+        assert!(d.outbounds_added.is_empty());
+    }
+}
@@ -1,7 +1,14 @@
-use std::env;
+pub mod diff;
+
+use std::collections::HashMap;
 use json_value_merge::Merge;
 use lazy_static::lazy_static;
+use prost::Message;
 use serde_json::{json, Value};
+use crate::proto::core::Config as CoreConfig;
+
+/// Blackhole outbound added to every config with outbounds
+pub const BLOCK_OUTBOUND: &str = "necko-block";
 
 lazy_static!(
     static ref API: Value = json!({
@@ -43,9 +50,7 @@ lazy_static!(
         "inbounds": [
             {
                 "listen": "127.0.0.1",
-                "port": env::var("XRAY_API_PORT")
-                    .map_err(|_| anyhow::anyhow!("XRAY_API_PORT not found"))
-                    .unwrap(),
+                "port": crate::settings::get().xray.api_port,
                 "protocol": "dokodemo-door",
                 "settings": {
                     "address": "127.0.0.1"
@@ -87,6 +92,8 @@ pub fn get_config_from_profile(
         None => json!({})
     };
 
+    check_shadowsocks_2022(&profile)?;
+
     profile.merge(&API.clone());
     profile.merge(&STATS.clone());
     profile.merge(&POLICY.clone());
@@ -106,16 +113,214 @@ pub fn get_config_from_profile(
 
     profile.merge(&json!({ "routing": profile_routing }));
 
+    // target of ip ban rules; appended so the profile's first outbound stays
+    // the default, and skipped without outbounds so it can't become it
+    if let Some(outbounds) = profile.get_mut("outbounds").and_then(Value::as_array_mut)
+        && !outbounds.is_empty()
+        && !outbounds.iter().any(|o| o["tag"] == BLOCK_OUTBOUND) {
+        outbounds.push(json!({ "protocol": "blackhole", "tag": BLOCK_OUTBOUND }));
+    }
+
     Ok(profile)
 }
 
-pub fn generate_config_from_profile(
+/// Without clients Xray builds a 2022 inbound as single-user, which has no
+/// way to add users at runtime, so every AddUser would fail. Multi-user
+/// inbounds only exist for the AES methods
+fn check_shadowsocks_2022(profile: &Value) -> anyhow::Result<()> {
+    for inbound in profile["inbounds"].as_array().into_iter().flatten() {
+        let settings = &inbound["settings"];
+        let method = settings["method"].as_str().unwrap_or_default();
+        let is_2022 = inbound["protocol"] == "shadowsocks" && method.starts_with("2022-");
+
+        if is_2022 && method == "2022-blake3-chacha20-poly1305" {
+            anyhow::bail!("Shadowsocks 2022 inbound {} uses {}, Xray only supports multiple \
+                users with 2022-blake3-aes-128-gcm and 2022-blake3-aes-256-gcm", inbound["tag"], method);
+        }
+
+        if is_2022 && settings["clients"].as_array().is_none_or(|c| c.is_empty()) {
+            anyhow::bail!("Shadowsocks 2022 inbound {} needs a `clients` array with at least \
+                one client, otherwise Xray cannot add users to it", inbound["tag"]);
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates the config and installs it only if Xray accepts it,
+/// so a broken profile never replaces a working config
+pub async fn generate_config_from_profile(
     path: Option<&str>
 ) -> anyhow::Result<Value> {
     let profile = get_config_from_profile(path)?;
 
-    std::fs::write("/etc/xray/config.json",
-                   serde_json::to_string_pretty(&profile)?)?;
+    install_config(&serde_json::to_string_pretty(&profile)?).await?;
 
     Ok(profile)
 }
+
+fn prev_path(config: &str) -> String {
+    format!("{}.prev", config)
+}
+
+/// Writes the config to a staging file next to the live one, lets Xray test
+/// it and renames it over the live config, keeping the old one as `.prev`
+async fn install_config(content: &str) -> anyhow::Result<()> {
+    let live = &crate::settings::get().xray.config;
+
+    // reinstalling the same config would overwrite .prev with it
+    if std::fs::read_to_string(live).is_ok_and(|current| current == content) {
+        return Ok(());
+    }
+
+    let staging = format!("{}.staging", live);
+    std::fs::write(&staging, content)?;
+
+    if let Err(e) = test_config(&staging).await {
+        let _ = std::fs::remove_file(&staging);
+        return Err(e);
+    }
+
+    if std::path::Path::new(live).exists() {
+        std::fs::copy(live, prev_path(live))?;
+    }
+    std::fs::rename(&staging, live)?;
+
+    Ok(())
+}
+
+/// Xray loads geo files for the test, which can take a while
+async fn test_config(path: &str) -> anyhow::Result<()> {
+    let bin = &crate::settings::get().xray.bin;
+
+    let output = tokio::process::Command::new(bin)
+        .args(["run", "-test", "-format", "json", "-config", path])
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {} to test the config: {}", bin, e))?;
+
+    if !output.status.success() {
+        anyhow::bail!("Xray rejected the config, the live one was kept:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr));
+    }
+
+    Ok(())
+}
+
+/// Swaps the live config with `.prev`, so rolling back twice undoes the rollback
+pub async fn rollback_config() -> anyhow::Result<Value> {
+    let live = &crate::settings::get().xray.config;
+    let prev = prev_path(live);
+    if !std::path::Path::new(&prev).exists() {
+        anyhow::bail!("No previous config to roll back to ({})", prev);
+    }
+
+    test_config(&prev).await?;
+
+    let staging = format!("{}.staging", live);
+    std::fs::copy(live, &staging)?;
+    std::fs::rename(&prev, live)?;
+    std::fs::rename(&staging, &prev)?;
+
+    read_current_config()
+}
+
+/// The config Xray is currently running with (or will start with)
+pub fn read_current_config() -> anyhow::Result<Value> {
+    let config = serde_json::from_str::<Value>(
+        &std::fs::read_to_string(&crate::settings::get().xray.config)?
+    )?;
+
+    Ok(config)
+}
+
+/// Whether the live config has the outbound ban rules send traffic to,
+/// which is missing when the profile has no outbounds
+pub fn has_block_outbound() -> bool {
+    read_current_config().is_ok_and(|config| config["outbounds"]
+        .as_array()
+        .is_some_and(|outbounds| outbounds.iter().any(|o| o["tag"] == BLOCK_OUTBOUND)))
+}
+
+/// What user management needs to know about an inbound of the live config
+#[derive(Debug, Clone)]
+pub struct LiveInbound {
+    /// e.g. `vless`
+    pub protocol: String,
+    /// `None` for port ranges and env or file ports
+    pub port: Option<u16>,
+    pub settings: Value,
+}
+
+/// Every tagged inbound of the live config, empty when there is no config yet
+pub fn live_inbounds() -> HashMap<String, LiveInbound> {
+    let Ok(config) = read_current_config() else { return HashMap::new() };
+
+    config["inbounds"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|inbound| Some((
+            inbound["tag"].as_str()?.to_string(),
+            LiveInbound {
+                protocol: inbound["protocol"].as_str()?.to_string(),
+                port: inbound["port"].as_u64().and_then(|port| u16::try_from(port).ok()),
+                settings: inbound["settings"].clone(),
+            },
+        )))
+        .collect()
+}
+
+/// Lets Xray itself translate the JSON config into the protobuf form its
+/// API expects, instead of reimplementing every protocol and transport here
+pub async fn current_config_protobuf() -> anyhow::Result<CoreConfig> {
+    let xray = &crate::settings::get().xray;
+    // own file per call, user requests, the online poll and reloads convert concurrently
+    let pb_path = format!("{}.{}.pb", xray.config, uuid::Uuid::new_v4());
+
+    let output = tokio::process::Command::new(&xray.bin)
+        .args(["convert", "pb", "-outpbfile", &pb_path, &xray.config])
+        .output()
+        .await;
+    let bytes = match output {
+        Ok(output) if output.status.success() => tokio::fs::read(&pb_path).await,
+        Ok(output) => {
+            let _ = tokio::fs::remove_file(&pb_path).await;
+            anyhow::bail!("xray convert failed: {}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr));
+        }
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&pb_path).await;
+    let bytes = bytes?;
+
+    Ok(CoreConfig::decode(bytes.as_slice())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_shadowsocks_2022_test() {
+        let mut profile = json!({
+            "inbounds": [{
+                "tag": "ss",
+                "protocol": "shadowsocks",
+                "settings": { "method": "2022-blake3-aes-128-gcm", "password": "a2V5" }
+            }]
+        });
+        assert!(check_shadowsocks_2022(&profile).is_err());
+
+        profile["inbounds"][0]["settings"]["clients"] = json!([{ "password": "a2V5", "email": "static" }]);
+        assert!(check_shadowsocks_2022(&profile).is_ok());
+
+        profile["inbounds"][0]["settings"]["method"] = json!("2022-blake3-chacha20-poly1305");
+        assert!(check_shadowsocks_2022(&profile).is_err());
+
+        profile["inbounds"][0]["settings"] = json!({ "method": "aes-256-gcm", "password": "x" });
+        assert!(check_shadowsocks_2022(&profile).is_ok());
+    }
+}
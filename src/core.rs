@@ -1,13 +1,23 @@
 use anyhow::{anyhow, bail};
-use crate::api::{daemon, Request};
-use crate::config::generate_config_from_profile;
-use clap::{Args, Subcommand};
+use crate::api::{daemon, Request, Response};
+use crate::config::{generate_config_from_profile, rollback_config};
+use crate::data::postgres::types::{
+    IpLimitPunishment, StatKind, TrafficBucketSize, UserListFilter, UserSort, VmessSecurity,
+};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args, Subcommand, ValueEnum};
 
 #[derive(Subcommand)]
 pub enum CoreCommands {
-    /// Choose profile
+    /// Choose profile (applied live if the daemon is running)
     Profile { path: String },
 
+    /// Re-read the active profile and apply changes without a restart
+    Reload,
+
+    /// Go back to the config replaced by the last profile change
+    Rollback,
+
     /// Show status
     #[command(subcommand)]
     Stats(StatsCommands),
@@ -31,6 +41,36 @@ pub enum StatsCommands {
 
     /// System stats
     System,
+
+    /// Stored traffic of a user, inbound or outbound over time
+    #[command(group(ArgGroup::new("target").required(true)))]
+    History {
+        #[arg(long, group = "target")]
+        user: Option<String>,
+        #[arg(long, group = "target")]
+        inbound: Option<String>,
+        #[arg(long, group = "target")]
+        outbound: Option<String>,
+
+        /// Start of the range (e.g. 30d, 2026-01-01)
+        #[arg(long)]
+        since: Option<String>,
+        /// End of the range, exclusive (e.g. 2026-02-01)
+        #[arg(long)]
+        until: Option<String>,
+
+        #[arg(long, value_enum, default_value = "day")]
+        bucket: TrafficBucketSize,
+
+        #[arg(long, value_enum, default_value = "json")]
+        format: HistoryFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryFormat {
+    Json,
+    Csv,
 }
 
 #[derive(Subcommand)]
@@ -39,7 +79,7 @@ pub enum UserStatsCommands {
     #[command(subcommand)]
     Online(UserStatsOnlineCommands),
 
-    /// Show traffic
+    /// Show traffic counted by Xray since it started, see `users show` for the stored total
     Traffic { email: String },
 }
 
@@ -78,8 +118,79 @@ pub enum UsersCommands {
     /// Delete user
     Delete { email: String },
 
+    /// Show a user's full record with live usage
+    Show { email: String },
+
+    /// Push the expiry date back, counting from now if already expired
+    Extend {
+        email: String,
+        /// e.g. 30d, 1mo
+        by: String,
+    },
+
+    /// Generate a new Shadowsocks 2022 key for a user
+    RotateKey { email: String },
+
+    /// Print a wg-quick config for a user's WireGuard peer
+    WgConfig {
+        email: String,
+        /// WireGuard inbound, needed when the user is on several
+        #[arg(long)]
+        inbound: Option<String>,
+    },
+
+    /// Show when and from which IPs a user was online
+    Sessions {
+        email: String,
+        /// Only sessions since then (e.g. 7d, 2026-01-31)
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Reset used traffic of a user or of every user with a tag
+    ResetTraffic {
+        #[arg(required_unless_present = "tag")]
+        email: Option<String>,
+        #[arg(long, conflicts_with = "email")]
+        tag: Option<String>,
+    },
+
     /// Get all users
     Get,
+
+    /// List users as JSON lines, filtered and a page at a time
+    List {
+        /// Users with this tag (repeat to require several)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Users in this inbound (repeat to require several)
+        #[arg(long = "inbound")]
+        inbounds: Vec<String>,
+        /// Only users currently in Xray
+        #[arg(long, conflicts_with = "disabled")]
+        active: bool,
+        /// Only inactive users or ones disabled by a limit
+        #[arg(long)]
+        disabled: bool,
+        #[arg(long)]
+        expired: bool,
+        /// e.g. 7d
+        #[arg(long)]
+        expiring_within: Option<String>,
+        #[arg(long)]
+        over_quota: bool,
+        /// Part of the email
+        #[arg(long)]
+        email: Option<String>,
+        #[arg(long, value_enum, default_value = "created")]
+        sort: UserSort,
+        /// Users per page, 0 for all
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+        /// Cursor printed at the end of the previous page
+        #[arg(long)]
+        after: Option<String>,
+    },
 }
 
 #[derive(Args, Debug)]
@@ -104,15 +215,42 @@ pub struct UserCommonArgs {
     #[arg(long)]
     pub ip_expire_after: Option<String>,
 
+    /// What to do over ip_limit: nothing, suspend:<time> or ban:<time>
+    /// (bans the newest IP)
+    #[arg(long)]
+    pub ip_limit_punishment: Option<IpLimitPunishment>,
+
     #[arg(long)]
     pub is_active: Option<bool>,
+
+    /// Date (2026-01-31, "2026-01-31 18:00", UTC) or offset from now (+30d)
+    #[arg(long)]
+    pub expire_at: Option<String>,
+
+    /// Cipher of the user's VMess account
+    #[arg(long, value_enum)]
+    pub vmess_security: Option<VmessSecurity>,
 }
 
 pub async fn handle_command(cmd: CoreCommands) -> anyhow::Result<()> {
     let request: Request = match cmd {
         CoreCommands::Profile { path } => {
-            let _ = generate_config_from_profile(Some(&format!("/etc/xray/profiles/{}", path)))?;
-            return Ok(())
+            if !daemon::lock::is_daemon_running() {
+                let _ = generate_config_from_profile(Some(&daemon::profile_path(&path))).await?;
+                return Ok(())
+            }
+
+            Request::ReloadProfile { profile: Some(path) }
+        },
+        CoreCommands::Reload => Request::ReloadProfile { profile: None },
+        CoreCommands::Rollback => {
+            if !daemon::lock::is_daemon_running() {
+                let _ = rollback_config().await?;
+                println!("Previous config restored");
+                return Ok(())
+            }
+
+            Request::RollbackConfig
         },
         CoreCommands::Stats(stats_cmd) => match stats_cmd {
             StatsCommands::User(user_cmd) => match user_cmd {
@@ -130,12 +268,44 @@ pub async fn handle_command(cmd: CoreCommands) -> anyhow::Result<()> {
             StatsCommands::Outbound { tag } =>
                 Request::GetStatsOutboundTraffic { tag },
             StatsCommands::System => Request::GetStatsSystem,
+            StatsCommands::History { user, inbound, outbound, since, until, bucket, format } => {
+                let (kind, name) = match (user, inbound, outbound) {
+                    (Some(email), _, _) => (StatKind::User, email),
+                    (_, Some(tag), _) => (StatKind::Inbound, tag),
+                    (_, _, Some(tag)) => (StatKind::Outbound, tag),
+                    _ => unreachable!("clap requires one target"),
+                };
+                let now = Utc::now();
+
+                let request = Request::GetTrafficHistory {
+                    kind,
+                    name,
+                    bucket,
+                    since: since.map(|s| crate::datetime::parse_since(&s, now)).transpose()?,
+                    until: until.map(|u| crate::datetime::parse_since(&u, now)).transpose()?,
+                };
+
+                match (format, daemon::send_request(request).await?) {
+                    (HistoryFormat::Csv, Response::TrafficHistory(history)) => {
+                        println!("start,uplink,downlink");
+                        for bucket in history {
+                            println!("{},{},{}", bucket.start.to_rfc3339(),
+                                     bucket.uplink, bucket.downlink);
+                        }
+                    }
+                    (_, response) => print_response(response),
+                }
+
+                return Ok(())
+            }
         },
         CoreCommands::Database(db_cmd) => match db_cmd {
             DatabaseCommands::Users(users_cmd) => match users_cmd {
                 UsersCommands::Create { email, args } => {
+                    let ip_limit_punishment = args.ip_limit_punishment.clone();
+                    let vmess_security = args.vmess_security.unwrap_or_default();
                     let (tags, inbounds, traffic_limit, reset_traffic_every,
-                        ip_limit, ip_expire_after, is_active) = build_user_fields(args)?;
+                        ip_limit, ip_expire_after, is_active, expire_at) = build_user_fields(args)?;
 
                     let traffic_limit = traffic_limit.unwrap_or(0);
                     let ip_limit = ip_limit.unwrap_or(0);
@@ -148,17 +318,20 @@ pub async fn handle_command(cmd: CoreCommands) -> anyhow::Result<()> {
                         inbounds,
                         traffic_limit,
                         reset_traffic_every,
-                        expire_at: None,
+                        expire_at,
                         ip_limit,
-                        ip_limit_punishment: None,
+                        ip_limit_punishment,
                         ip_expire_after,
                         is_active,
+                        vmess_security,
                     }
                 }
 
                 UsersCommands::Update { email, args } => {
+                    let ip_limit_punishment = args.ip_limit_punishment.clone();
+                    let vmess_security = args.vmess_security;
                     let (tags, inbounds, traffic_limit, reset_traffic_every,
-                        ip_limit, ip_expire_after, is_active) = build_user_fields(args)?;
+                        ip_limit, ip_expire_after, is_active, expire_at) = build_user_fields(args)?;
 
                     Request::UpdateUser {
                         email,
@@ -166,27 +339,90 @@ pub async fn handle_command(cmd: CoreCommands) -> anyhow::Result<()> {
                         inbounds,
                         traffic_limit,
                         reset_traffic_every,
-                        expire_at: None,
+                        expire_at,
                         ip_limit,
-                        ip_limit_punishment: None,
+                        ip_limit_punishment,
                         ip_expire_after,
                         is_active,
+                        vmess_security,
                     }
                 },
                 UsersCommands::Delete { email } =>
                     Request::DeleteUser { email },
+                UsersCommands::Show { email } =>
+                    Request::GetUser { email },
+                UsersCommands::Extend { email, by } => Request::ExtendUser {
+                    email,
+                    seconds: crate::datetime::parse_seconds(&by)? as i64,
+                },
+                UsersCommands::RotateKey { email } =>
+                    Request::RotateShadowsocksKey { email },
+                UsersCommands::WgConfig { email, inbound } =>
+                    Request::GetWireguardConfig { email, inbound },
+                UsersCommands::Sessions { email, since } => Request::GetUserSessions {
+                    email,
+                    since: since
+                        .map(|s| crate::datetime::parse_since(&s, Utc::now()))
+                        .transpose()?,
+                },
+                UsersCommands::ResetTraffic { email, tag } =>
+                    Request::ResetTraffic { email, tag },
                 UsersCommands::Get =>
                     Request::GetAllUsers,
+                UsersCommands::List { tags, inbounds, active, disabled, expired,
+                    expiring_within, over_quota, email, sort, limit, after } => {
+                    let filter = UserListFilter {
+                        tags,
+                        inbounds,
+                        enabled: (active || disabled).then_some(active),
+                        expired,
+                        expiring_within: expiring_within
+                            .map(|e| crate::datetime::parse_seconds(&e).map(|s| s as i64))
+                            .transpose()?,
+                        over_quota,
+                        email_contains: email,
+                        sort,
+                        after,
+                        limit: (limit > 0).then_some(limit),
+                    };
+
+                    daemon::stream_request(Request::ListUsers(filter), |response| {
+                        match response {
+                            Response::User(user) => match serde_json::to_string(&user) {
+                                Ok(line) => println!("{}", line),
+                                Err(e) => eprintln!("[necko-xray]: {}", e),
+                            },
+                            Response::UserListEnd { next: None } => {}
+                            response @ Response::UserListEnd { .. } =>
+                                eprintln!("[necko-xray]: {}", response),
+                            response => print_response(response),
+                        }
+                    }).await?;
+
+                    return Ok(())
+                }
             },
         },
     };
 
     let response = daemon::send_request(request).await?;
-    println!("{}", response);
-    
+    print_response(response);
+
     Ok(())
 }
 
+/// Prints a daemon response; errors go to stderr and end the process with
+/// the exit code of their `ErrorCode`
+pub fn print_response(response: Response) {
+    if let Response::Error { code, message } = &response {
+        eprintln!("[necko-xray]: {}", message);
+        std::process::exit(code.exit_code());
+    }
+
+    println!("{}", response);
+}
+
+#[allow(clippy::type_complexity)]
 fn build_user_fields(args: UserCommonArgs) -> anyhow::Result<(
     Option<Vec<String>>,      // tags
     Option<Vec<String>>,      // inbounds
@@ -194,9 +430,10 @@ fn build_user_fields(args: UserCommonArgs) -> anyhow::Result<(
     Option<i64>,              // reset_traffic_every
     Option<i64>,              // ip_limit
     Option<i64>,              // ip_expire_after
-    Option<bool>              // is_active
+    Option<bool>,             // is_active
+    Option<DateTime<Utc>>,    // expire_at
 )> {
-    let traffic_limit: i64 = match args.traffic_limit {
+    let traffic_limit: i64 = match &args.traffic_limit {
         Some(s) => {
             let s = s.trim().to_uppercase();
             let idx = s
@@ -218,7 +455,8 @@ fn build_user_fields(args: UserCommonArgs) -> anyhow::Result<(
         }
         None => 0,
     };
-    let traffic_limit = Some(traffic_limit);
+    // an update without --traffic-limit keeps the current limit
+    let traffic_limit = args.traffic_limit.is_some().then_some(traffic_limit);
 
     let reset_traffic_every = args
         .reset_traffic_every
@@ -228,6 +466,10 @@ fn build_user_fields(args: UserCommonArgs) -> anyhow::Result<(
         .ip_expire_after
         .map(|iea| crate::datetime::parse_seconds(&iea).unwrap() as i64);
     let is_active = args.is_active;
+    let expire_at = args
+        .expire_at
+        .map(|e| crate::datetime::parse_datetime(&e, Utc::now()))
+        .transpose()?;
 
     let tags = args.tags.map(|t| {
         t.split(',')
@@ -241,5 +483,6 @@ fn build_user_fields(args: UserCommonArgs) -> anyhow::Result<(
             .collect::<Vec<_>>()
     });
 
-    Ok((tags, inbounds, traffic_limit, reset_traffic_every, ip_limit, ip_expire_after, is_active))
+    Ok((tags, inbounds, traffic_limit, reset_traffic_every, ip_limit, ip_expire_after, is_active,
+        expire_at))
 }
@@ -3,9 +3,12 @@ use sqlx::postgres::PgPoolOptions;
 
 pub mod postgres;
 
-pub async fn create_db_pool(database_url: &str) -> Result<PgPool, sqlx::Error> {
+pub async fn create_db_pool(
+    database_url: &str,
+    max_connections: u32,
+) -> Result<PgPool, sqlx::Error> {
     PgPoolOptions::new()
-        .max_connections(10)
+        .max_connections(max_connections)
         .connect(database_url)
         .await
 }
\ No newline at end of file
@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data::postgres::types::IpBan;
+
+pub async fn create_ip_ban(
+    pool: &PgPool,
+    user_id: Uuid,
+    ip: &str,
+    banned_until: DateTime<Utc>,
+) -> Result<IpBan, sqlx::Error> {
+    sqlx::query_as::<_, IpBan>(
+        r#"
+        WITH ban AS (
+            INSERT INTO ip_bans (user_id, ip, banned_until)
+            VALUES ($1, $2, $3)
+            RETURNING *
+        )
+        SELECT ban.*, users.email FROM ban JOIN users ON users.id = ban.user_id;
+        "#
+    )
+        .bind(user_id)
+        .bind(ip)
+        .bind(banned_until)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn get_active_ip_bans(
+    pool: &PgPool
+) -> Result<Vec<IpBan>, sqlx::Error> {
+    sqlx::query_as::<_, IpBan>(
+        r#"
+        SELECT ip_bans.*, users.email FROM ip_bans
+        JOIN users ON users.id = ip_bans.user_id
+        WHERE banned_until > NOW()
+        ORDER BY ip_bans.created_at;
+        "#
+    )
+        .fetch_all(pool)
+        .await
+}
+
+/// Deletes the bans that are over and returns their ids
+pub async fn take_expired_ip_bans(
+    pool: &PgPool
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        r#"
+        DELETE FROM ip_bans WHERE banned_until <= NOW() RETURNING id;
+        "#
+    )
+        .fetch_all(pool)
+        .await
+}
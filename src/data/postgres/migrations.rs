@@ -0,0 +1,190 @@
+use chrono::{DateTime, Utc};
+use sqlx::migrate::{MigrateError, Migration, Migrator};
+use sqlx::{PgConnection, PgPool};
+
+/// `migrations/*.up.sql` and `*.down.sql`, embedded at build time
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Held while migrating so two daemons or CLIs never run the same migration
+const LOCK_ID: i64 = 0x6e65636b6f; // "necko"
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    /// `None` while pending
+    pub applied_at: Option<DateTime<Utc>>,
+    /// Applied by a newer necko-xray, this build has no such migration
+    pub unknown: bool,
+}
+
+async fn ensure_table(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version      BIGINT PRIMARY KEY,
+            description  TEXT NOT NULL,
+            checksum     BYTEA NOT NULL,
+            applied_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        "#
+    ).execute(&mut *conn).await?;
+
+    Ok(())
+}
+
+async fn applied(conn: &mut PgConnection) -> Result<Vec<(i64, Vec<u8>, String, DateTime<Utc>)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT version, checksum, description, applied_at
+        FROM schema_migrations
+        ORDER BY version;
+        "#
+    )
+        .fetch_all(&mut *conn)
+        .await
+}
+
+fn ups() -> impl Iterator<Item = &'static Migration> {
+    MIGRATOR.iter().filter(|m| m.migration_type.is_up_migration())
+}
+
+fn down(version: i64) -> Option<&'static Migration> {
+    MIGRATOR.iter().find(|m| m.version == version && m.migration_type.is_down_migration())
+}
+
+/// Applies every pending migration in its own transaction and returns
+/// them. Databases created before migrations existed have tables but no
+/// `schema_migrations`; the early migrations only create what is missing,
+/// so such a database is adopted by running them as usual
+pub async fn migrate(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)").bind(LOCK_ID).execute(&mut *conn).await?;
+
+    let result = migrate_locked(&mut conn).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)").bind(LOCK_ID).execute(&mut *conn).await?;
+    result
+}
+
+async fn migrate_locked(conn: &mut PgConnection) -> Result<Vec<MigrationStatus>, MigrateError> {
+    ensure_table(conn).await?;
+    let applied = applied(conn).await?;
+
+    for (version, checksum, _, _) in &applied {
+        match ups().find(|m| m.version == *version) {
+            Some(m) if *m.checksum != **checksum => return Err(MigrateError::VersionMismatch(*version)),
+            Some(_) => {}
+            // a newer build already ran it, an older one must not touch the schema
+            None => return Err(MigrateError::VersionMissing(*version)),
+        }
+    }
+
+    if applied.is_empty() {
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass('users') IS NOT NULL")
+            .fetch_one(&mut *conn)
+            .await?;
+        if exists {
+            println!("[necko-xray]: Adopting existing database into schema_migrations");
+        }
+    }
+
+    let mut done = Vec::new();
+    for migration in ups().filter(|m| !applied.iter().any(|a| a.0 == m.version)) {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+        sqlx::raw_sql(&migration.sql)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| MigrateError::ExecuteMigration(e, migration.version))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO schema_migrations (version, description, checksum)
+            VALUES ($1, $2, $3);
+            "#
+        )
+            .bind(migration.version)
+            .bind(&*migration.description)
+            .bind(&*migration.checksum)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        println!("[necko-xray]: Applied migration {} {}", migration.version, migration.description);
+        done.push(MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            applied_at: Some(Utc::now()),
+            unknown: false,
+        });
+    }
+
+    Ok(done)
+}
+
+/// Every migration this build knows plus any applied by a newer one
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    ensure_table(&mut conn).await?;
+    let applied = applied(&mut conn).await?;
+
+    let mut statuses: Vec<MigrationStatus> = ups()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            applied_at: applied.iter().find(|a| a.0 == m.version).map(|a| a.3),
+            unknown: false,
+        })
+        .collect();
+
+    for (version, _, description, applied_at) in applied {
+        if !statuses.iter().any(|s| s.version == version) {
+            statuses.push(MigrationStatus {
+                version,
+                description,
+                applied_at: Some(applied_at),
+                unknown: true,
+            });
+        }
+    }
+    statuses.sort_by_key(|s| s.version);
+
+    Ok(statuses)
+}
+
+/// Reverts the newest applied migration, `None` when there is none
+pub async fn rollback(pool: &PgPool) -> Result<Option<MigrationStatus>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)").bind(LOCK_ID).execute(&mut *conn).await?;
+
+    let result = rollback_locked(&mut conn).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)").bind(LOCK_ID).execute(&mut *conn).await?;
+    result
+}
+
+async fn rollback_locked(conn: &mut PgConnection) -> Result<Option<MigrationStatus>, MigrateError> {
+    ensure_table(conn).await?;
+    let Some((version, _, description, _)) = applied(conn).await?.pop() else {
+        return Ok(None);
+    };
+    let migration = down(version).ok_or(MigrateError::VersionMissing(version))?;
+
+    let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+    sqlx::raw_sql(&migration.sql)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| MigrateError::ExecuteMigration(e, version))?;
+
+    sqlx::query("DELETE FROM schema_migrations WHERE version = $1;")
+        .bind(version)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(MigrationStatus { version, description, applied_at: None, unknown: false }))
+}
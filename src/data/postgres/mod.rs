@@ -1,10 +1,13 @@
+pub mod bans;
+pub mod migrations;
+pub mod sessions;
+pub mod traffic;
 pub mod types;
 pub mod users;
+pub mod wireguard;
 
-use sqlx::PgPool;
+pub use bans::*;
+pub use sessions::*;
+pub use traffic::*;
 pub use users::*;
-
-pub async fn init_database(pool: &PgPool) -> Result<(), sqlx::Error> {
-    users::init_database(pool).await?;
-    Ok(())
-}
\ No newline at end of file
+pub use wireguard::*;
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data::postgres::types::UserSession;
+
+/// Extends the session of every (user, IP) seen within `gap` seconds
+/// before `at`, opens a new one for the others, and sets the users'
+/// `last_online_at`. One poll of all users goes in as a single batch
+pub async fn record_sessions(
+    pool: &PgPool,
+    seen: &[(Uuid, String)],
+    at: DateTime<Utc>,
+    gap: i64,
+) -> Result<(), sqlx::Error> {
+    if seen.is_empty() {
+        return Ok(());
+    }
+
+    let (user_ids, ips): (Vec<Uuid>, Vec<String>) = seen.iter().cloned().unzip();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        WITH seen AS (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[]) AS t(user_id, ip)
+        ),
+        open AS (
+            SELECT DISTINCT ON (s.user_id, s.ip) s.id, s.user_id, s.ip
+            FROM user_sessions s
+            JOIN seen ON seen.user_id = s.user_id AND seen.ip = s.ip
+            WHERE s.last_seen >= $3 - $4 * INTERVAL '1 second'
+            ORDER BY s.user_id, s.ip, s.last_seen DESC
+        ),
+        extended AS (
+            UPDATE user_sessions SET last_seen = $3
+            FROM open WHERE user_sessions.id = open.id
+            RETURNING open.user_id, open.ip
+        )
+        INSERT INTO user_sessions (user_id, ip, first_seen, last_seen)
+        SELECT seen.user_id, seen.ip, $3, $3 FROM seen
+        WHERE NOT EXISTS (
+            SELECT 1 FROM extended e WHERE e.user_id = seen.user_id AND e.ip = seen.ip
+        );
+        "#
+    )
+        .bind(&user_ids)
+        .bind(&ips)
+        .bind(at)
+        .bind(gap)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE users SET last_online_at = $2 WHERE id = ANY($1);
+        "#
+    )
+        .bind(&user_ids)
+        .bind(at)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Sessions of a user that were still going on at or after `since`,
+/// newest first
+pub async fn get_user_sessions(
+    pool: &PgPool,
+    user_id: Uuid,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<UserSession>, sqlx::Error> {
+    sqlx::query_as::<_, UserSession>(
+        r#"
+        SELECT ip, first_seen, last_seen,
+               EXTRACT(EPOCH FROM last_seen - first_seen)::BIGINT AS online_seconds
+        FROM user_sessions
+        WHERE user_id = $1 AND ($2::timestamptz IS NULL OR last_seen >= $2)
+        ORDER BY last_seen DESC;
+        "#
+    )
+        .bind(user_id)
+        .bind(since)
+        .fetch_all(pool)
+        .await
+}
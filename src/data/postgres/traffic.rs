@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::data::postgres::types::{StatKind, TrafficBucket, TrafficBucketSize};
+
+/// Adds what each user's counters grew since the last checkpoint to
+/// `traffic_used` and moves the checkpoints in the same transaction.
+/// `counters` are cumulative (uplink, downlink) of the Xray process `epoch`.
+/// Returns the users whose traffic changed
+pub async fn record_traffic(
+    pool: &PgPool,
+    epoch: Uuid,
+    counters: &HashMap<String, (i64, i64)>,
+) -> Result<usize, sqlx::Error> {
+    if counters.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let emails: Vec<String> = counters.keys().cloned().collect();
+    let checkpoints = sqlx::query_as::<_, (Uuid, String, Option<Uuid>, Option<i64>, Option<i64>)>(
+        r#"
+        SELECT u.id, u.email, c.epoch, c.uplink, c.downlink
+        FROM users u
+        LEFT JOIN traffic_checkpoints c ON c.user_id = u.id
+        WHERE u.email = ANY($1)
+        FOR UPDATE OF u;
+        "#
+    )
+        .bind(&emails)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut ids = Vec::with_capacity(checkpoints.len());
+    let mut names = Vec::with_capacity(checkpoints.len());
+    let mut used = Vec::with_capacity(checkpoints.len());
+    let mut grown_up = Vec::with_capacity(checkpoints.len());
+    let mut grown_down = Vec::with_capacity(checkpoints.len());
+    let mut uplinks = Vec::with_capacity(checkpoints.len());
+    let mut downlinks = Vec::with_capacity(checkpoints.len());
+
+    for (id, email, last_epoch, last_up, last_down) in checkpoints {
+        let (up, down) = counters[&email];
+        let (up_delta, down_delta) = growth(epoch, (up, down), last_epoch, last_up, last_down);
+
+        ids.push(id);
+        names.push(email);
+        used.push(up_delta + down_delta);
+        grown_up.push(up_delta);
+        grown_down.push(down_delta);
+        uplinks.push(up);
+        downlinks.push(down);
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE users SET traffic_used = traffic_used + d.used
+        FROM UNNEST($1::uuid[], $2::bigint[]) AS d(id, used)
+        WHERE users.id = d.id AND d.used > 0;
+        "#
+    )
+        .bind(&ids)
+        .bind(&used)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO traffic_checkpoints (user_id, epoch, uplink, downlink)
+        SELECT d.id, $2, d.uplink, d.downlink
+        FROM UNNEST($1::uuid[], $3::bigint[], $4::bigint[]) AS d(id, uplink, downlink)
+        ON CONFLICT (user_id) DO UPDATE
+        SET epoch = EXCLUDED.epoch,
+            uplink = EXCLUDED.uplink,
+            downlink = EXCLUDED.downlink,
+            updated_at = NOW();
+        "#
+    )
+        .bind(&ids)
+        .bind(epoch)
+        .bind(&uplinks)
+        .bind(&downlinks)
+        .execute(&mut *tx)
+        .await?;
+
+    record_samples(&mut tx, StatKind::User, &names, &grown_up, &grown_down).await?;
+
+    tx.commit().await?;
+
+    Ok(used.iter().filter(|bytes| **bytes > 0).count())
+}
+
+/// `record_traffic` for inbound or outbound counters, which only feed
+/// the samples
+pub async fn record_link_traffic(
+    pool: &PgPool,
+    epoch: Uuid,
+    kind: StatKind,
+    counters: &HashMap<String, (i64, i64)>,
+) -> Result<(), sqlx::Error> {
+    if counters.is_empty() {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let names: Vec<String> = counters.keys().cloned().collect();
+    let checkpoints = sqlx::query_as::<_, (String, Option<Uuid>, Option<i64>, Option<i64>)>(
+        r#"
+        SELECT n.name, c.epoch, c.uplink, c.downlink
+        FROM UNNEST($2::text[]) AS n(name)
+        LEFT JOIN stat_checkpoints c ON c.kind = $1 AND c.name = n.name;
+        "#
+    )
+        .bind(kind)
+        .bind(&names)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut names = Vec::with_capacity(checkpoints.len());
+    let mut grown_up = Vec::with_capacity(checkpoints.len());
+    let mut grown_down = Vec::with_capacity(checkpoints.len());
+    let mut uplinks = Vec::with_capacity(checkpoints.len());
+    let mut downlinks = Vec::with_capacity(checkpoints.len());
+
+    for (name, last_epoch, last_up, last_down) in checkpoints {
+        let (up, down) = counters[&name];
+        let (up_delta, down_delta) = growth(epoch, (up, down), last_epoch, last_up, last_down);
+
+        names.push(name);
+        grown_up.push(up_delta);
+        grown_down.push(down_delta);
+        uplinks.push(up);
+        downlinks.push(down);
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO stat_checkpoints (kind, name, epoch, uplink, downlink)
+        SELECT $1, d.name, $3, d.uplink, d.downlink
+        FROM UNNEST($2::text[], $4::bigint[], $5::bigint[]) AS d(name, uplink, downlink)
+        ON CONFLICT (kind, name) DO UPDATE
+        SET epoch = EXCLUDED.epoch,
+            uplink = EXCLUDED.uplink,
+            downlink = EXCLUDED.downlink,
+            updated_at = NOW();
+        "#
+    )
+        .bind(kind)
+        .bind(&names)
+        .bind(epoch)
+        .bind(&uplinks)
+        .bind(&downlinks)
+        .execute(&mut *tx)
+        .await?;
+
+    record_samples(&mut tx, kind, &names, &grown_up, &grown_down).await?;
+
+    tx.commit().await
+}
+
+/// Stores a sample for every name that moved any bytes and adds it to the
+/// rollups of the current hour, day and month
+async fn record_samples(
+    conn: &mut PgConnection,
+    kind: StatKind,
+    names: &[String],
+    uplinks: &[i64],
+    downlinks: &[i64],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO traffic_samples (kind, name, sampled_at, uplink, downlink)
+        SELECT $1, d.name, NOW(), d.uplink, d.downlink
+        FROM UNNEST($2::text[], $3::bigint[], $4::bigint[]) AS d(name, uplink, downlink)
+        WHERE d.uplink + d.downlink > 0;
+        "#
+    )
+        .bind(kind)
+        .bind(names)
+        .bind(uplinks)
+        .bind(downlinks)
+        .execute(&mut *conn)
+        .await?;
+
+    let buckets: Vec<&str> = TrafficBucketSize::ALL.iter().map(|b| b.as_str()).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO traffic_rollups (kind, name, bucket, bucket_start, uplink, downlink)
+        SELECT $1, d.name, b.bucket, date_trunc(b.bucket, NOW(), 'UTC'), d.uplink, d.downlink
+        FROM UNNEST($2::text[], $3::bigint[], $4::bigint[]) AS d(name, uplink, downlink)
+        CROSS JOIN UNNEST($5::text[]) AS b(bucket)
+        WHERE d.uplink + d.downlink > 0
+        ON CONFLICT (kind, name, bucket, bucket_start) DO UPDATE
+        SET uplink = traffic_rollups.uplink + EXCLUDED.uplink,
+            downlink = traffic_rollups.downlink + EXCLUDED.downlink;
+        "#
+    )
+        .bind(kind)
+        .bind(names)
+        .bind(uplinks)
+        .bind(downlinks)
+        .bind(&buckets)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes samples older than `retention` seconds, the rollups stay
+pub async fn prune_traffic_samples(pool: &PgPool, retention: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM traffic_samples
+        WHERE sampled_at < NOW() - $1 * INTERVAL '1 second';
+        "#
+    )
+        .bind(retention)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Rollups of one counter owner, oldest first. Buckets overlapping
+/// `since` are included, `until` is exclusive
+pub async fn get_traffic_history(
+    pool: &PgPool,
+    kind: StatKind,
+    name: &str,
+    bucket: TrafficBucketSize,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> Result<Vec<TrafficBucket>, sqlx::Error> {
+    sqlx::query_as::<_, TrafficBucket>(
+        r#"
+        SELECT bucket_start AS start, uplink, downlink
+        FROM traffic_rollups
+        WHERE kind = $1 AND name = $2 AND bucket = $3
+          AND ($4::timestamptz IS NULL OR bucket_start >= date_trunc($3, $4, 'UTC'))
+          AND ($5::timestamptz IS NULL OR bucket_start < $5)
+        ORDER BY bucket_start;
+        "#
+    )
+        .bind(kind)
+        .bind(name)
+        .bind(bucket)
+        .bind(since)
+        .bind(until)
+        .fetch_all(pool)
+        .await
+}
+
+/// Growth of (uplink, downlink) since a checkpoint
+fn growth(
+    epoch: Uuid,
+    (up, down): (i64, i64),
+    last_epoch: Option<Uuid>,
+    last_up: Option<i64>,
+    last_down: Option<i64>,
+) -> (i64, i64) {
+    if last_epoch == Some(epoch) {
+        (grown(up, last_up.unwrap_or(0)), grown(down, last_down.unwrap_or(0)))
+    } else {
+        // a new Xray process counts from zero
+        (up, down)
+    }
+}
+
+/// A counter below its checkpoint was reset in between, all of it is new
+fn grown(value: i64, last: i64) -> i64 {
+    if value >= last { value - last } else { value }
+}
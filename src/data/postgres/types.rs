@@ -5,18 +5,190 @@ use std::collections::HashMap;
 use sqlx::types::Json;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(tag = "type")]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IpLimitPunishment {
     Nothing,
     SuspendUser { time: i64 },
     BanLastIp { time: i64 },
 }
 
+/// CLI form: `nothing`, `suspend:30m` or `ban:1h`
+impl std::str::FromStr for IpLimitPunishment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, time) = s.trim().split_once(':').unwrap_or((s.trim(), ""));
+        let time = || crate::datetime::parse_seconds(time)
+            .map_err(|e| e.to_string())
+            .and_then(|t| match t {
+                0 => Err(format!("`{}` needs a duration, e.g. `{}:30m`", kind, kind)),
+                t => Ok(t as i64),
+            });
+
+        match kind.to_lowercase().as_str() {
+            "nothing" | "none" => Ok(IpLimitPunishment::Nothing),
+            "suspend" => Ok(IpLimitPunishment::SuspendUser { time: time()? }),
+            "ban" => Ok(IpLimitPunishment::BanLastIp { time: time()? }),
+            _ => Err(format!("Unknown punishment `{}`, use nothing, suspend:<time> or ban:<time>",
+                             kind)),
+        }
+    }
+}
+
+/// Stored in JSONB as `{"type": "SuspendUser", "time": 60}`. Bincode (the
+/// control socket) cannot decode internally tagged enums, so non
+/// human-readable formats get the externally tagged form instead
+mod punishment_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use super::IpLimitPunishment;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Tagged {
+        Nothing,
+        SuspendUser { time: i64 },
+        BanLastIp { time: i64 },
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Plain {
+        Nothing,
+        SuspendUser { time: i64 },
+        BanLastIp { time: i64 },
+    }
+
+    impl Serialize for IpLimitPunishment {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match (self.clone(), serializer.is_human_readable()) {
+                (IpLimitPunishment::Nothing, true) => Tagged::Nothing.serialize(serializer),
+                (IpLimitPunishment::SuspendUser { time }, true) =>
+                    Tagged::SuspendUser { time }.serialize(serializer),
+                (IpLimitPunishment::BanLastIp { time }, true) =>
+                    Tagged::BanLastIp { time }.serialize(serializer),
+                (IpLimitPunishment::Nothing, false) => Plain::Nothing.serialize(serializer),
+                (IpLimitPunishment::SuspendUser { time }, false) =>
+                    Plain::SuspendUser { time }.serialize(serializer),
+                (IpLimitPunishment::BanLastIp { time }, false) =>
+                    Plain::BanLastIp { time }.serialize(serializer),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for IpLimitPunishment {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            if deserializer.is_human_readable() {
+                Ok(match Tagged::deserialize(deserializer)? {
+                    Tagged::Nothing => IpLimitPunishment::Nothing,
+                    Tagged::SuspendUser { time } => IpLimitPunishment::SuspendUser { time },
+                    Tagged::BanLastIp { time } => IpLimitPunishment::BanLastIp { time },
+                })
+            } else {
+                Ok(match Plain::deserialize(deserializer)? {
+                    Plain::Nothing => IpLimitPunishment::Nothing,
+                    Plain::SuspendUser { time } => IpLimitPunishment::SuspendUser { time },
+                    Plain::BanLastIp { time } => IpLimitPunishment::BanLastIp { time },
+                })
+            }
+        }
+    }
+}
+
+/// Cipher of a user's VMess account, stored as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, sqlx::Type,
+    clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum VmessSecurity {
+    /// AES-128-GCM on hardware with AES instructions, ChaCha20 elsewhere
+    #[default]
+    Auto,
+    #[value(name = "aes-128-gcm")]
+    #[serde(rename = "aes-128-gcm")]
+    #[sqlx(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    Chacha20Poly1305,
+    None,
+    Zero,
+}
+
+/// Why an active user is kept out of Xray, stored as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum DisabledReason {
+    /// traffic_used reached traffic_limit
+    TrafficLimit,
+    /// expire_at has passed
+    Expired,
+    /// Suspended until suspended_until for using more IPs than ip_limit
+    IpLimit,
+}
+
+/// Owner of a set of Xray traffic counters, stored as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum StatKind {
+    User,
+    Inbound,
+    Outbound,
+}
+
+impl StatKind {
+    /// First part of the Xray counter name, e.g. `inbound>>>`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatKind::User => "user",
+            StatKind::Inbound => "inbound",
+            StatKind::Outbound => "outbound",
+        }
+    }
+}
+
+/// Granularity of the traffic rollups, stored as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum TrafficBucketSize {
+    Hour,
+    Day,
+    Month,
+}
+
+impl TrafficBucketSize {
+    pub const ALL: [TrafficBucketSize; 3] =
+        [TrafficBucketSize::Hour, TrafficBucketSize::Day, TrafficBucketSize::Month];
+
+    /// Postgres `date_trunc` field
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficBucketSize::Hour => "hour",
+            TrafficBucketSize::Day => "day",
+            TrafficBucketSize::Month => "month",
+        }
+    }
+}
+
+/// Traffic of one counter owner within one bucket, starting at `start` (UTC)
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct TrafficBucket {
+    pub start: DateTime<Utc>,
+    pub uplink: i64,
+    pub downlink: i64,
+}
+
 #[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: Uuid,
     pub email: String,
+    /// Generated secret for password based protocols (Trojan, SOCKS, HTTP).
+    /// SOCKS and HTTP log in with the email as username
+    pub password: String,
+    /// VMess inbounds use `id` with this cipher
+    pub vmess_security: VmessSecurity,
+    /// Base64 of 32 random bytes, cut to the key size of each
+    /// Shadowsocks 2022 inbound's method
+    pub ss_key: String,
     pub tags: Option<Vec<String>>,
     /// Inbounds to add user to
     pub inbounds: Option<Vec<String>>,
@@ -36,10 +208,97 @@ pub struct User {
     /// Expire IP from ip_list after X seconds (0 = never)
     pub ip_expire_after: i64,
     pub is_active: bool,
+    /// Set while the daemon keeps the user out of Xray
+    pub disabled_reason: Option<DisabledReason>,
+    /// End of an ip_limit suspension
+    pub suspended_until: Option<DateTime<Utc>>,
+    /// Last time the online poller saw any IP of the user
+    pub last_online_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Order of `users list`; ties are broken by id so pages never overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, sqlx::Type,
+    clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum UserSort {
+    /// Oldest first
+    #[default]
+    Created,
+    /// Most traffic used first
+    Usage,
+    /// Soonest expiry first, never expiring last
+    Expiry,
+}
+
+/// Filters of `users list`, `None`/`false`/empty means any
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserListFilter {
+    /// Users having all of these tags
+    pub tags: Vec<String>,
+    /// Users in all of these inbounds
+    pub inbounds: Vec<String>,
+    /// `true` for users in Xray, `false` for inactive or disabled ones
+    pub enabled: Option<bool>,
+    pub expired: bool,
+    /// Not expired yet but will within this many seconds
+    pub expiring_within: Option<i64>,
+    /// traffic_used reached a non-zero traffic_limit
+    pub over_quota: bool,
+    /// Case-insensitive part of the email
+    pub email_contains: Option<String>,
+    pub sort: UserSort,
+    /// `next` of the previous page
+    pub after: Option<String>,
+    /// Page size, `None` streams every match
+    pub limit: Option<u32>,
+}
+
+/// Time an IP of a user was continuously online
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct UserSession {
+    pub ip: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub online_seconds: i64,
+}
+
+/// WireGuard identity of a user on one inbound
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct WireguardPeer {
+    pub user_id: Uuid,
+    pub inbound_tag: String,
+    /// Tunnel IPv4 address from `wireguard.address_pool`
+    pub address: String,
+    pub private_key: String,
+    pub public_key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Source IP of a user blocked by a routing rule until `banned_until`
+#[derive(Debug, FromRow, Clone)]
+pub struct IpBan {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub email: String,
+    pub ip: String,
+    pub banned_until: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IpBan {
+    /// Tag of the routing rule, to remove it again
+    pub fn rule_tag(&self) -> String {
+        ban_rule_tag(self.id)
+    }
+}
+
+pub fn ban_rule_tag(id: Uuid) -> String {
+    format!("necko-ban-{}", id)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateUser {
     pub email: String,
@@ -58,6 +317,7 @@ pub struct CreateUser {
     /// Expire IP from ip_list after X seconds (0 = never)
     pub ip_expire_after: i64,
     pub is_active: bool,
+    pub vmess_security: VmessSecurity,
 }
 
 impl Default for CreateUser {
@@ -73,6 +333,26 @@ impl Default for CreateUser {
             ip_limit_punishment: None,
             ip_expire_after: 0,
             is_active: true,
+            vmess_security: VmessSecurity::Auto,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::config::standard;
+
+    #[test]
+    fn ip_limit_punishment_serde_test() {
+        let punishment = IpLimitPunishment::SuspendUser { time: 60 };
+
+        let json = serde_json::to_string(&punishment).unwrap();
+        assert_eq!(json, r#"{"type":"SuspendUser","time":60}"#);
+        assert_eq!(serde_json::from_str::<IpLimitPunishment>(&json).unwrap(), punishment);
+
+        let bytes = bincode::serde::encode_to_vec(&punishment, standard()).unwrap();
+        let (decoded, _): (IpLimitPunishment, usize) =
+            bincode::serde::decode_from_slice(&bytes, standard()).unwrap();
+        assert_eq!(decoded, punishment);
+    }
+}
@@ -1,78 +1,10 @@
-use sqlx::PgPool;
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool, Row};
 use sqlx::types::Json;
 use uuid::Uuid;
 use crate::api::Request;
-use crate::data::postgres::types::{CreateUser, User};
-
-pub async fn init_database(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(r#"CREATE EXTENSION IF NOT EXISTS pgcrypto;"#)
-        .execute(pool)
-        .await?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id                     UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            email                  VARCHAR(255) UNIQUE NOT NULL,
-
-            tags                   TEXT[],
-            inbounds               TEXT[],
-
-            traffic_limit          BIGINT NOT NULL DEFAULT 0,
-            traffic_used           BIGINT NOT NULL DEFAULT 0,
-
-            reset_traffic_every    BIGINT,
-            last_traffic_reset_at  TIMESTAMPTZ,
-
-            expire_at              TIMESTAMPTZ,
-
-            ip_limit               BIGINT NOT NULL DEFAULT 0,
-            ip_list                JSONB,
-            ip_limit_punishment    JSONB,
-            ip_expire_after        BIGINT NOT NULL DEFAULT 0,
-
-            is_active              BOOLEAN NOT NULL DEFAULT true,
-            created_at             TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-            updated_at             TIMESTAMPTZ NOT NULL DEFAULT NOW()
-        );
-        "#
-    ).execute(pool).await?;
-
-    sqlx::query(
-        r#"
-        CREATE OR REPLACE FUNCTION set_updated_at()
-        RETURNS TRIGGER AS $$
-        BEGIN
-            NEW.updated_at := NOW();
-            RETURN NEW;
-        END;
-        $$ LANGUAGE plpgsql;
-        "#
-    ).execute(pool).await?;
-
-    // Trigger
-    sqlx::query(
-        r#"
-        DO $$
-        BEGIN
-            IF NOT EXISTS (
-                SELECT 1
-                FROM pg_trigger
-                WHERE tgname = 'set_timestamp'
-            ) THEN
-                CREATE TRIGGER set_timestamp
-                BEFORE UPDATE ON users
-                FOR EACH ROW
-                EXECUTE FUNCTION set_updated_at();
-            END IF;
-        END;
-        $$;
-        "#
-    ).execute(pool).await?;
-
-    println!("Database initialized successfully");
-    Ok(())
-}
+use crate::data::postgres::types::{CreateUser, DisabledReason, User, UserListFilter};
 
 pub async fn create_user(
     pool: &PgPool,
@@ -90,8 +22,9 @@ pub async fn create_user(
             ip_limit,
             ip_limit_punishment,
             ip_expire_after,
-            is_active
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            is_active,
+            vmess_security
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         RETURNING *;
         "#
     )
@@ -105,6 +38,7 @@ pub async fn create_user(
         .bind(data.ip_limit_punishment)
         .bind(data.ip_expire_after)
         .bind(data.is_active)
+        .bind(data.vmess_security)
         .fetch_one(pool)
         .await?;
 
@@ -140,6 +74,301 @@ pub async fn get_all_users(
     Ok(users)
 }
 
+/// Up to `limit` users matching `filter` in its sort order, starting after
+/// the row whose sort key and id are `after`. Each user comes with its sort
+/// key, which together with the id is the position to continue from
+pub async fn list_users(
+    pool: &PgPool,
+    filter: &UserListFilter,
+    after: Option<(i64, Uuid)>,
+    limit: i64,
+) -> Result<Vec<(User, i64)>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT * FROM (
+            SELECT users.*,
+                   CASE $1
+                       WHEN 'usage' THEN -traffic_used
+                       WHEN 'expiry' THEN COALESCE(
+                           (EXTRACT(EPOCH FROM expire_at) * 1000000)::bigint,
+                           9223372036854775807)
+                       ELSE (EXTRACT(EPOCH FROM created_at) * 1000000)::bigint
+                   END AS sort_key
+            FROM users
+        ) u
+        WHERE ($2::text[] IS NULL OR tags @> $2)
+          AND ($3::text[] IS NULL OR inbounds @> $3)
+          AND ($4::boolean IS NULL OR (is_active AND disabled_reason IS NULL) = $4)
+          AND (NOT $5 OR expire_at <= NOW())
+          AND ($6::bigint IS NULL
+               OR (expire_at > NOW() AND expire_at <= NOW() + $6 * INTERVAL '1 second'))
+          AND (NOT $7 OR (traffic_limit > 0 AND traffic_used >= traffic_limit))
+          AND ($8::text IS NULL OR strpos(lower(email), lower($8)) > 0)
+          AND ($9::bigint IS NULL OR (sort_key, id) > ($9, $10))
+        ORDER BY sort_key, id
+        LIMIT $11;
+        "#
+    )
+        .bind(filter.sort)
+        .bind((!filter.tags.is_empty()).then_some(&filter.tags))
+        .bind((!filter.inbounds.is_empty()).then_some(&filter.inbounds))
+        .bind(filter.enabled)
+        .bind(filter.expired)
+        .bind(filter.expiring_within)
+        .bind(filter.over_quota)
+        .bind(&filter.email_contains)
+        .bind(after.map(|(key, _)| key))
+        .bind(after.map(|(_, id)| id))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter()
+        .map(|row| Ok((User::from_row(row)?, row.try_get("sort_key")?)))
+        .collect()
+}
+
+/// Users that must be present in Xray inbounds
+pub async fn get_active_users(
+    pool: &PgPool
+) -> Result<Vec<User>, sqlx::Error> {
+    let users = sqlx::query_as::<_, User>(
+        r#"
+        SELECT * FROM users
+        WHERE is_active = true AND disabled_reason IS NULL
+        ORDER BY created_at;
+        "#
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(users)
+}
+
+/// Why a user should be kept out of Xray right now, NULL when nothing stops them
+const WANTED_DISABLED_REASON: &str = r#"
+    CASE
+        WHEN expire_at IS NOT NULL AND expire_at <= NOW() THEN 'expired'
+        WHEN suspended_until IS NOT NULL AND suspended_until > NOW() THEN 'ip_limit'
+        WHEN traffic_limit > 0 AND traffic_used >= traffic_limit THEN 'traffic_limit'
+    END
+"#;
+
+/// Users whose `disabled_reason` no longer matches their limits,
+/// with the reason they should have
+pub async fn get_users_to_reconcile(
+    pool: &PgPool
+) -> Result<Vec<(User, Option<DisabledReason>)>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT *, {wanted} AS wanted_reason FROM users
+        WHERE disabled_reason IS DISTINCT FROM ({wanted})
+        ORDER BY created_at;
+        "#,
+        wanted = WANTED_DISABLED_REASON,
+    ))
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter()
+        .map(|row| Ok((User::from_row(row)?, row.try_get("wanted_reason")?)))
+        .collect()
+}
+
+/// When the next user expires or gets out of a suspension, to wake up right then
+pub async fn get_next_deadline(
+    pool: &PgPool
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+        r#"
+        SELECT MIN(at) FROM (
+            SELECT expire_at AS at FROM users
+            UNION ALL
+            SELECT suspended_until FROM users
+        ) deadlines
+        WHERE at > NOW();
+        "#
+    )
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn set_ip_list(
+    pool: &PgPool,
+    id: Uuid,
+    ip_list: &HashMap<String, i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE users SET ip_list = $2 WHERE id = $1;
+        "#
+    )
+        .bind(id)
+        .bind(Json(ip_list))
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Replaces the ip_list of every user in `ip_lists` in one statement
+pub async fn set_ip_lists(
+    pool: &PgPool,
+    ip_lists: &HashMap<Uuid, HashMap<String, i64>>,
+) -> Result<(), sqlx::Error> {
+    if ip_lists.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE users SET ip_list = l.value
+        FROM jsonb_each($1) AS l
+        WHERE users.id = l.key::uuid;
+        "#
+    )
+        .bind(Json(ip_lists))
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Starts an ip_limit suspension; the IPs seen so far are forgotten so the
+/// user doesn't get suspended again right after it ends
+pub async fn suspend_user(
+    pool: &PgPool,
+    id: Uuid,
+    until: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE users SET suspended_until = $2, ip_list = '{}'::jsonb WHERE id = $1;
+        "#
+    )
+        .bind(id)
+        .bind(until)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Moves expire_at `seconds` forward, from now if it already passed.
+/// `None` when the user doesn't exist or never expires
+pub async fn extend_user(
+    pool: &PgPool,
+    email: &str,
+    seconds: i64,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET expire_at = GREATEST(expire_at, NOW()) + $2 * INTERVAL '1 second'
+        WHERE email = $1 AND expire_at IS NOT NULL
+        RETURNING *;
+        "#
+    )
+        .bind(email)
+        .bind(seconds)
+        .fetch_optional(pool)
+        .await
+}
+
+pub enum TrafficResetTarget {
+    /// Users whose `reset_traffic_every` elapsed since the last reset
+    Due,
+    Email(String),
+    Tag(String),
+}
+
+/// (email, password) of the enabled users on an inbound, the accounts
+/// of SOCKS and HTTP inbounds
+pub async fn get_inbound_accounts(
+    pool: &PgPool,
+    tag: &str,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT email, password FROM users
+        WHERE $1 = ANY(inbounds) AND is_active AND disabled_reason IS NULL
+        ORDER BY email;
+        "#
+    )
+        .bind(tag)
+        .fetch_all(pool)
+        .await
+}
+
+/// Replaces the user's Shadowsocks secret with a new random one
+pub async fn rotate_ss_key(
+    pool: &PgPool,
+    email: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET ss_key = encode(gen_random_bytes(32), 'base64')
+        WHERE email = $1
+        RETURNING *;
+        "#
+    )
+        .bind(email)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Zeroes `traffic_used`, returns the emails of the users that were reset
+pub async fn reset_traffic(
+    pool: &PgPool,
+    target: &TrafficResetTarget,
+) -> Result<Vec<String>, sqlx::Error> {
+    let query = match target {
+        TrafficResetTarget::Due => sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE users SET traffic_used = 0, last_traffic_reset_at = NOW()
+            WHERE reset_traffic_every > 0
+              AND COALESCE(last_traffic_reset_at, created_at)
+                  + reset_traffic_every * INTERVAL '1 second' <= NOW()
+            RETURNING email;
+            "#
+        ),
+        TrafficResetTarget::Email(email) => sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE users SET traffic_used = 0, last_traffic_reset_at = NOW()
+            WHERE email = $1
+            RETURNING email;
+            "#
+        ).bind(email),
+        TrafficResetTarget::Tag(tag) => sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE users SET traffic_used = 0, last_traffic_reset_at = NOW()
+            WHERE tags @> ARRAY[$1]
+            RETURNING email;
+            "#
+        ).bind(tag),
+    };
+
+    query.fetch_all(pool).await
+}
+
+pub async fn set_disabled_reason(
+    pool: &PgPool,
+    id: Uuid,
+    reason: Option<DisabledReason>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE users SET disabled_reason = $2 WHERE id = $1;
+        "#
+    )
+        .bind(id)
+        .bind(reason)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
 pub async fn get_user_by_id(
     pool: &PgPool,
     id: Uuid
@@ -241,13 +470,13 @@ pub async fn update_user(
     req: Request,
 ) -> Result<User, sqlx::Error> {
     let (email, tags, inbounds, traffic_limit, reset_traffic_every, expire_at, ip_limit,
-        ip_limit_punishment, ip_expire_after, is_active) = match req {
+        ip_limit_punishment, ip_expire_after, is_active, vmess_security) = match req {
             Request::UpdateUser { email, tags, inbounds,
                 traffic_limit, reset_traffic_every, expire_at,
                 ip_limit, ip_limit_punishment, ip_expire_after,
-                is_active } => {
+                is_active, vmess_security } => {
                 (email, tags, inbounds, traffic_limit, reset_traffic_every, expire_at, ip_limit,
-                 ip_limit_punishment, ip_expire_after, is_active)
+                 ip_limit_punishment, ip_expire_after, is_active, vmess_security)
             },
             _ => {
                 return Err(sqlx::Error::InvalidArgument("Invalid request".to_string()));
@@ -266,7 +495,8 @@ pub async fn update_user(
             ip_limit            = COALESCE($7, ip_limit),
             ip_limit_punishment = COALESCE($8, ip_limit_punishment),
             ip_expire_after     = COALESCE($9, ip_expire_after),
-            is_active           = COALESCE($10, is_active)
+            is_active           = COALESCE($10, is_active),
+            vmess_security      = COALESCE($11, vmess_security)
         WHERE email = $1
         RETURNING *;
         "#
@@ -281,6 +511,7 @@ pub async fn update_user(
     .bind(ip_limit_punishment.map(Json))  // Option<IpLimitPunishment> -> Option<Json<_>>
     .bind(ip_expire_after)                // Option<i64>
     .bind(is_active)                      // Option<bool>
+    .bind(vmess_security)                 // Option<VmessSecurity>
     .fetch_one(pool)
     .await?;
 
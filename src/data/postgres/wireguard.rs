@@ -0,0 +1,108 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::data::postgres::types::WireguardPeer;
+
+/// Deletes the peers of users that left the inbound, freeing their
+/// addresses. Returns the affected inbound tags
+pub async fn release_stale_peers(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        DELETE FROM wireguard_peers p
+        USING users u
+        WHERE p.user_id = u.id
+          AND NOT (p.inbound_tag = ANY(COALESCE(u.inbounds, '{}')))
+        RETURNING p.inbound_tag;
+        "#
+    )
+        .fetch_all(pool)
+        .await
+}
+
+/// Users on the inbound without a peer yet, oldest first so allocation
+/// order does not depend on when it runs
+pub async fn get_users_without_peer(
+    pool: &PgPool,
+    tag: &str,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT u.id FROM users u
+        WHERE $1 = ANY(u.inbounds)
+          AND NOT EXISTS (
+              SELECT 1 FROM wireguard_peers p
+              WHERE p.user_id = u.id AND p.inbound_tag = $1
+          )
+        ORDER BY u.created_at, u.id;
+        "#
+    )
+        .bind(tag)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_peer_addresses(pool: &PgPool, tag: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT address FROM wireguard_peers WHERE inbound_tag = $1;
+        "#
+    )
+        .bind(tag)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn create_peer(
+    pool: &PgPool,
+    user_id: Uuid,
+    tag: &str,
+    address: &str,
+    private_key: &str,
+    public_key: &str,
+) -> Result<WireguardPeer, sqlx::Error> {
+    sqlx::query_as::<_, WireguardPeer>(
+        r#"
+        INSERT INTO wireguard_peers (user_id, inbound_tag, address, private_key, public_key)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING *;
+        "#
+    )
+        .bind(user_id)
+        .bind(tag)
+        .bind(address)
+        .bind(private_key)
+        .bind(public_key)
+        .fetch_one(pool)
+        .await
+}
+
+/// Peers Xray should accept on the inbound: those of enabled users
+pub async fn get_enabled_peers(
+    pool: &PgPool,
+    tag: &str,
+) -> Result<Vec<WireguardPeer>, sqlx::Error> {
+    sqlx::query_as::<_, WireguardPeer>(
+        r#"
+        SELECT p.* FROM wireguard_peers p
+        JOIN users u ON u.id = p.user_id
+        WHERE p.inbound_tag = $1 AND u.is_active AND u.disabled_reason IS NULL
+        ORDER BY p.user_id;
+        "#
+    )
+        .bind(tag)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_user_peers(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<WireguardPeer>, sqlx::Error> {
+    sqlx::query_as::<_, WireguardPeer>(
+        r#"
+        SELECT * FROM wireguard_peers WHERE user_id = $1 ORDER BY inbound_tag;
+        "#
+    )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+}
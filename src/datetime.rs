@@ -1,4 +1,5 @@
 use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeDelta, Utc};
 
 pub fn parse_seconds(input: &str) -> Result<u64> {
     let mut num = String::new();
@@ -63,11 +64,53 @@ pub fn parse_seconds(input: &str) -> Result<u64> {
     Ok(total)
 }
 
+/// Absolute date (`2026-01-31`, `2026-01-31 18:00`, RFC 3339; UTC unless
+/// an offset is given) or an offset from `now` like `+30d`
+pub fn parse_datetime(input: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let input = input.trim();
+
+    if let Some(offset) = input.strip_prefix('+') {
+        let seconds = parse_seconds(offset)?;
+        let seconds = i64::try_from(seconds).map_err(|_| anyhow!("Offset `{offset}` is too big"))?;
+        return now
+            .checked_add_signed(TimeDelta::seconds(seconds))
+            .ok_or_else(|| anyhow!("Offset `{offset}` is too big"));
+    }
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(input) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(date.and_utc());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+
+    bail!("Invalid date `{input}`, expected e.g. `2026-01-31`, `2026-01-31 18:00` or `+30d`")
+}
+
+/// Start of a range: a duration back from `now` (`7d`) or a date as in
+/// `parse_datetime`
+pub fn parse_since(input: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    match parse_seconds(input) {
+        Ok(seconds) => {
+            let seconds = i64::try_from(seconds).map_err(|_| anyhow!("`{input}` is too far back"))?;
+            now.checked_sub_signed(TimeDelta::seconds(seconds))
+                .ok_or_else(|| anyhow!("`{input}` is too far back"))
+        }
+        Err(_) => parse_datetime(input, now),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn parse_seconds_test() {
         assert_eq!(parse_seconds("2s").unwrap(), 2);
         assert_eq!(parse_seconds("1d").unwrap(), 86400);
@@ -75,4 +118,22 @@ mod tests {
         assert_eq!(parse_seconds("30min30sec").unwrap(), 30*60 + 30);
         assert_eq!(parse_seconds("2s 1d 48h 9w").unwrap(), 2 + 1*86400 + 48*3600 + 9*7*86400);
     }
+
+    #[test]
+    fn parse_datetime_test() {
+        let now = "2026-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(parse_datetime("+30d", now).unwrap(), now + TimeDelta::days(30));
+        assert_eq!(parse_datetime("2026-02-01", now).unwrap(),
+                   "2026-02-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(parse_datetime("2026-02-01 18:30", now).unwrap(),
+                   "2026-02-01T18:30:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(parse_datetime("2026-02-01T18:30:00+03:00", now).unwrap(),
+                   "2026-02-01T15:30:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert!(parse_datetime("tomorrow", now).is_err());
+
+        assert_eq!(parse_since("7d", now).unwrap(), now - TimeDelta::days(7));
+        assert_eq!(parse_since("2025-12-25", now).unwrap(),
+                   "2025-12-25T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    }
 }
@@ -1,8 +1,13 @@
 use crate::proto::app::proxyman::command::{AddUserOperation, AlterInboundRequest, RemoveUserOperation};
-use crate::proto::app::stats::command::{GetStatsRequest, SysStatsRequest, SysStatsResponse};
-use crate::proto::common::protocol::User;
-use crate::proto::common::serial;
+use crate::proto::app::stats::command::{
+    GetAllOnlineUsersRequest, GetStatsRequest, QueryStatsRequest, SysStatsRequest, SysStatsResponse,
+};
+use crate::proto::common::protocol::{SecurityConfig, SecurityType, User};
+use crate::proto::common::serial::{self, TypedMessage};
+use crate::proto::proxy::shadowsocks_2022::Account as Shadowsocks2022Account;
+use crate::proto::proxy::trojan::Account as TrojanAccount;
 use crate::proto::proxy::vless::Account as VlessAccount;
+use crate::proto::proxy::vmess::Account as VmessAccount;
 use proto::{
     app::{
         log::command::logger_service_client::LoggerServiceClient,
@@ -13,8 +18,7 @@ use proto::{
     core::observatory::command::observatory_service_client::ObservatoryServiceClient,
     transport::internet::grpc::grpc_service_client::GrpcServiceClient,
 };
-use std::collections::{HashMap, HashSet};
-use std::env;
+use std::collections::HashMap;
 use tonic::transport::{Channel, Endpoint};
 
 pub mod proto;
@@ -23,14 +27,11 @@ pub mod api;
 pub mod config;
 pub mod data;
 pub mod datetime;
+pub mod settings;
 
 pub async fn connect() -> anyhow::Result<Channel> {
     let channel = Endpoint::try_from(
-        format!(
-            "https://127.0.0.1:{}",
-            env::var("XRAY_API_PORT")
-                .map_err(|_| anyhow::anyhow!("XRAY_API_PORT not found"))?
-        ))?
+        format!("https://127.0.0.1:{}", settings::get().xray.api_port))?
         .connect()
         .await?;
 
@@ -95,6 +96,26 @@ impl Client {
         Ok(resp.ips)
     }
 
+    /// Emails of the users with at least one online IP
+    pub async fn online_users(&self) -> anyhow::Result<Vec<String>> {
+        let mut client = self.stats();
+
+        let names = client
+            .get_all_online_users(GetAllOnlineUsersRequest {})
+            .await?
+            .into_inner()
+            .users;
+
+        Ok(names
+            .iter()
+            .map(|name| name
+                .strip_prefix("user>>>")
+                .and_then(|n| n.strip_suffix(">>>online"))
+                .unwrap_or(name)
+                .to_string())
+            .collect())
+    }
+
     pub async fn user_online_count(&self, email: &str) -> anyhow::Result<i64> {
         let mut client = self.stats();
 
@@ -128,6 +149,64 @@ impl Client {
         self.some_traffic("user", email).await
     }
 
+    /// Traffic of every user Xray has counters for, by email, in one call
+    pub async fn all_users_traffic(
+        &self,
+        reset: bool,
+    ) -> anyhow::Result<HashMap<String, (i64, i64)>> {
+        self.all_traffic("user", reset).await
+    }
+
+    /// Traffic of every `user`, `inbound` or `outbound` Xray has counters
+    /// for, by email or tag, in one call
+    pub async fn all_traffic(
+        &self,
+        traffic_from: &str,
+        reset: bool,
+    ) -> anyhow::Result<HashMap<String, (i64, i64)>> {
+        let mut client = self.stats();
+
+        let pattern = format!("{}>>>", traffic_from);
+        let stats = client
+            .query_stats(QueryStatsRequest { pattern, reset })
+            .await?
+            .into_inner()
+            .stat;
+
+        let mut traffic: HashMap<String, (i64, i64)> = HashMap::new();
+        for stat in stats {
+            // {traffic_from}>>>{name}>>>traffic>>>{uplink|downlink}
+            let mut parts = stat.name.split(">>>");
+            let (Some(from), Some(name), Some("traffic"), Some(direction)) =
+                (parts.next(), parts.next(), parts.next(), parts.next()) else {
+                continue;
+            };
+            if from != traffic_from {
+                continue;
+            }
+
+            let entry = traffic.entry(name.to_string()).or_default();
+            match direction {
+                "uplink" => entry.0 += stat.value,
+                "downlink" => entry.1 += stat.value,
+                _ => {}
+            }
+        }
+
+        Ok(traffic)
+    }
+
+    /// Zeroes the user's counters, so a user created again with the same
+    /// email does not start with the old one's traffic
+    pub async fn reset_user_traffic(&self, email: &str) -> anyhow::Result<()> {
+        let pattern = format!("user>>>{}>>>traffic>>>", email);
+        self.stats()
+            .query_stats(QueryStatsRequest { pattern, reset: true })
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn inbound_traffic(
         &self,
         tag: &str
@@ -179,8 +258,6 @@ impl Client {
         id: &str,
         email: &str
     ) -> anyhow::Result<()> {
-        let mut client = self.handler();
-
         let account = VlessAccount {
             id: id.to_string(),
             flow: "".to_string(),
@@ -188,10 +265,64 @@ impl Client {
             ..Default::default()
         };
 
+        self.add_user(inbound_tag, email,
+                      serial::to_typed_message(&account, "xray.proxy.vless.Account")).await
+    }
+
+    pub async fn add_vmess_user(
+        &self,
+        inbound_tag: &str,
+        id: &str,
+        security: SecurityType,
+        email: &str
+    ) -> anyhow::Result<()> {
+        let account = VmessAccount {
+            id: id.to_string(),
+            security_settings: Some(SecurityConfig { r#type: security as i32 }),
+            ..Default::default()
+        };
+
+        self.add_user(inbound_tag, email,
+                      serial::to_typed_message(&account, "xray.proxy.vmess.Account")).await
+    }
+
+    pub async fn add_trojan_user(
+        &self,
+        inbound_tag: &str,
+        password: &str,
+        email: &str
+    ) -> anyhow::Result<()> {
+        let account = TrojanAccount { password: password.to_string() };
+
+        self.add_user(inbound_tag, email,
+                      serial::to_typed_message(&account, "xray.proxy.trojan.Account")).await
+    }
+
+    /// `key` is the user's base64 key sized for the inbound's 2022 method
+    pub async fn add_shadowsocks_2022_user(
+        &self,
+        inbound_tag: &str,
+        key: &str,
+        email: &str
+    ) -> anyhow::Result<()> {
+        let account = Shadowsocks2022Account { key: key.to_string() };
+
+        self.add_user(inbound_tag, email,
+                      serial::to_typed_message(&account, "xray.proxy.shadowsocks_2022.Account")).await
+    }
+
+    async fn add_user(
+        &self,
+        inbound_tag: &str,
+        email: &str,
+        account: TypedMessage,
+    ) -> anyhow::Result<()> {
+        let mut client = self.handler();
+
         let inbound_user = User {
             level: 0,
             email: email.to_string(),
-            account: Some(serial::to_typed_message(&account, "proxy.vless.Account")),
+            account: Some(account),
         };
 
         let op = AddUserOperation { user: Some(inbound_user) };
@@ -200,7 +331,7 @@ impl Client {
             tag: inbound_tag.to_string(),
             operation: Some(serial::to_typed_message(
                 &op,
-                "proxyman.command.AddUserOperation"
+                "xray.app.proxyman.command.AddUserOperation"
             )),
         };
 
@@ -212,6 +343,15 @@ impl Client {
         &self,
         inbound_tag: &str,
         email: &str
+    ) -> anyhow::Result<()> {
+        self.remove_user(inbound_tag, email).await
+    }
+
+    /// Removes a user by email, whatever the protocol of the inbound
+    pub async fn remove_user(
+        &self,
+        inbound_tag: &str,
+        email: &str
     ) -> anyhow::Result<()> {
         let mut client = self.handler();
 
@@ -221,32 +361,11 @@ impl Client {
             tag: inbound_tag.to_string(),
             operation: Some(serial::to_typed_message(
                 &op,
-                "xray.proxyman.command.RemoveUserOperation"
+                "xray.app.proxyman.command.RemoveUserOperation"
             )),
         };
 
         let _ = client.alter_inbound(req).await?;
         Ok(())
     }
-
-    pub async fn sync_user_inbounds(
-        &self,
-        email: &str,
-        id: &str,
-        old_inbounds: Vec<String>,
-        new_inbounds: Vec<String>,
-    ) -> anyhow::Result<()> {
-        let old: HashSet<_> = old_inbounds.into_iter().collect();
-        let new: HashSet<_> = new_inbounds.into_iter().collect();
-
-        for tag in old.difference(&new) {
-            let _ = self.remove_vless_user(tag, email).await;
-        }
-
-        for tag in new.difference(&old) {
-            self.add_vless_user(tag, id, email).await?;
-        }
-
-        Ok(())
-    }
-}
\ No newline at end of file
+}
@@ -1,12 +1,21 @@
+use std::io::Write;
+use chrono::{Duration, Utc};
 use clap::{Parser, Subcommand};
 use necko_xray::api::daemon;
+use necko_xray::api::daemon::logs::{LogFilter, LogLevel};
+use necko_xray::data::postgres::migrations;
+use necko_xray::datetime::parse_seconds;
 use necko_xray::api::Request;
-use necko_xray::core::CoreCommands;
+use necko_xray::core::{print_response, CoreCommands};
 
 #[derive(Parser)]
 #[command(name = "necko-xray")]
 #[command(about = "A lightweight CLI xray-core wrapper, written on rust")]
 struct Cli {
+    /// Daemon config file (default: $NECKO_CONFIG or /etc/necko-xray/necko.toml)
+    #[arg(long, global = true)]
+    config: Option<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -22,23 +31,62 @@ enum Commands {
     /// Restart the daemon
     Restart,
 
+    /// Show Xray process status
+    Status,
+
+    /// Show Xray output
+    Logs {
+        /// Keep printing new lines
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Minimal level to show
+        #[arg(long, value_enum)]
+        level: Option<LogLevel>,
+
+        /// Only lines newer than this (e.g. 10m, 1h)
+        #[arg(long)]
+        since: Option<String>,
+    },
+
     /// Current version
     Version,
 
     /// Core (API) commands
     #[command(subcommand)]
     Core(CoreCommands),
+
+    /// Database schema migrations (talks to Postgres directly)
+    #[command(subcommand)]
+    Db(DbCommands),
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Apply pending migrations, the daemon also does this on start
+    Migrate,
+
+    /// List migrations and whether they are applied
+    Status,
+
+    /// Revert the newest applied migration (the daemon must be stopped)
+    Rollback,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
+    if let Err(e) = necko_xray::settings::init(cli.config.as_deref()) {
+        eprintln!("[necko-xray]: {}", e);
+        std::process::exit(1);
+    }
+
     match cli.command {
         Some(Commands::Start) => {
             if daemon::lock::is_daemon_running() {
                 let resp = daemon::send_request(Request::StartXray).await?;
-                println!("[necko-xray]: {}", resp);
+                print_response(resp);
                 return Ok(());
             }
 
@@ -51,25 +99,88 @@ async fn main() -> anyhow::Result<()> {
         }
         Some(Commands::Stop) => {
             let resp = daemon::send_request(Request::StopXray).await?;
-            println!("[necko-xray]: {}", resp);
+            print_response(resp);
         }
         Some(Commands::Restart) => {
             if daemon::lock::is_daemon_running() {
                 let resp = daemon::send_request(Request::RestartXray).await?;
-                println!("[necko-xray]: {}", resp);
+                print_response(resp);
             } else {
                 eprintln!("[necko-xray]: Daemon is not running");
                 std::process::exit(1);
             }
         }
+        Some(Commands::Status) => {
+            let resp = daemon::send_request(Request::XrayStatus).await?;
+            print_response(resp);
+        }
+        Some(Commands::Logs { follow, level, since }) => {
+            let since = match since {
+                Some(s) => Some(Utc::now() - Duration::seconds(parse_seconds(&s)? as i64)),
+                None => None,
+            };
+
+            let filter = LogFilter { follow, level, since };
+            daemon::stream_request(Request::Logs(filter), |response| {
+                print_response(response);
+                let _ = std::io::stdout().flush();
+            }).await?;
+        }
         Some(Commands::Core(cmd)) => {
             necko_xray::core::handle_command(cmd).await?;
         }
+        Some(Commands::Db(cmd)) => {
+            if let Err(e) = db_command(cmd).await {
+                eprintln!("[necko-xray]: {:#}", e);
+                std::process::exit(1);
+            }
+        }
         None | Some(Commands::Version) => {
             println!("v{}", env!("CARGO_PKG_VERSION"));
+            match daemon::connect().await {
+                Ok((_, hello)) => println!("daemon v{} (protocol {}), supports: {}",
+                                           hello.version, hello.protocol_version,
+                                           hello.capabilities.join(", ")),
+                Err(e) if daemon::lock::is_daemon_running() => println!("daemon: {}", e),
+                Err(_) => {}
+            }
             println!("necko-xray help")
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+async fn db_command(cmd: DbCommands) -> anyhow::Result<()> {
+    if matches!(cmd, DbCommands::Rollback) && daemon::lock::is_daemon_running() {
+        anyhow::bail!("Stop the daemon before rolling back the schema");
+    }
+
+    let settings = necko_xray::settings::get();
+    let pool = necko_xray::data::create_db_pool(&settings.database_url()?, 1).await?;
+
+    match cmd {
+        DbCommands::Migrate => {
+            if migrations::migrate(&pool).await?.is_empty() {
+                println!("Schema is up to date");
+            }
+        }
+        DbCommands::Status => {
+            for status in migrations::status(&pool).await? {
+                let state = match status.applied_at {
+                    Some(at) if status.unknown => format!("applied {} (unknown to this build)", at),
+                    Some(at) => format!("applied {}", at),
+                    None => "pending".to_string(),
+                };
+                println!("{:>4}  {:<24} {}", status.version, status.description, state);
+            }
+        }
+        DbCommands::Rollback => match migrations::rollback(&pool).await? {
+            Some(status) => println!("Reverted migration {} {}",
+                                     status.version, status.description),
+            None => println!("No migrations to revert"),
+        },
+    }
+
+    Ok(())
+}
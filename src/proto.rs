@@ -32,6 +32,7 @@ pub mod app {
     }
 
     /// https://xtls.github.io/en/config/policy.html
+    #[allow(clippy::module_inception)] // generated `Policy` has a nested `policy` module
     pub mod policy {
         tonic::include_proto!("xray.app.policy");
     }
@@ -67,7 +68,7 @@ pub mod app {
 
             tonic::include_proto!("xray.app.stats.command");
 
-            #[derive(Serialize, Deserialize)]
+            #[derive(Serialize, Deserialize, Debug, Clone)]
             pub struct SysStatsResponseSerializable {
                 pub num_goroutine: u32,
                 pub num_gc: u32,
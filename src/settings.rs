@@ -0,0 +1,299 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use serde::Deserialize;
+
+pub const DEFAULT_PATH: &str = "/etc/necko-xray/necko.toml";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Daemon configuration, read from `necko.toml` (see `necko.example.toml`)
+/// with environment variables taking precedence over the file
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub daemon: DaemonSettings,
+    pub xray: XraySettings,
+    pub database: DatabaseSettings,
+    pub accounting: AccountingSettings,
+    pub online: OnlineSettings,
+    pub wireguard: WireguardSettings,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonSettings {
+    /// Control socket the CLI talks to
+    pub socket_path: String,
+    pub pid_file: String,
+    /// Permissions of the socket file, e.g. `0o660`
+    pub socket_mode: u32,
+    /// Group owning the socket file (name or gid)
+    pub socket_group: Option<String>,
+    /// Clients allowed to change things (root and the daemon's own user always are)
+    pub admin_uids: Vec<u32>,
+    pub admin_gids: Vec<u32>,
+    /// Clients allowed to read status, stats and logs only
+    pub readonly_uids: Vec<u32>,
+    pub readonly_gids: Vec<u32>,
+}
+
+impl Default for DaemonSettings {
+    fn default() -> Self {
+        Self {
+            socket_path: "/tmp/necko-xray.sock".to_string(),
+            pid_file: "/tmp/necko-xray.pid".to_string(),
+            socket_mode: 0o660,
+            socket_group: None,
+            admin_uids: vec![],
+            admin_gids: vec![],
+            readonly_uids: vec![],
+            readonly_gids: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct XraySettings {
+    pub bin: String,
+    /// Generated config Xray runs with
+    pub config: String,
+    pub pid_file: String,
+    pub profiles_dir: String,
+    /// Profile loaded on daemon start until `reload <profile>` picks another
+    pub profile: String,
+    pub api_port: u16,
+    /// Also keep captured Xray output in this (rotated) file
+    pub log_file: Option<String>,
+    /// Echo captured Xray output to the daemon's stdout
+    pub log_stdout: bool,
+}
+
+impl Default for XraySettings {
+    fn default() -> Self {
+        Self {
+            bin: "/usr/local/bin/xray".to_string(),
+            config: "/etc/xray/config.json".to_string(),
+            pid_file: "/tmp/necko-xray-core.pid".to_string(),
+            profiles_dir: "/etc/xray/profiles".to_string(),
+            profile: "example.json".to_string(),
+            api_port: 10085,
+            log_file: None,
+            log_stdout: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    /// Built from POSTGRES_USER/PASSWORD/DB when not set
+    pub url: Option<String>,
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            max_connections: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingSettings {
+    /// Seconds between moving Xray traffic counters into the database
+    pub interval: u64,
+    /// Seconds to keep raw traffic samples, hourly and coarser rollups stay
+    pub sample_retention: u64,
+}
+
+impl Default for AccountingSettings {
+    fn default() -> Self {
+        Self { interval: 60, sample_retention: 7 * 24 * 60 * 60 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnlineSettings {
+    /// Seconds between polling the online IPs of every user
+    pub poll_interval: u64,
+}
+
+impl Default for OnlineSettings {
+    fn default() -> Self {
+        Self { poll_interval: 10 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WireguardSettings {
+    /// IPv4 network peers get their tunnel address from, the first host
+    /// is left for the server side
+    pub address_pool: String,
+    /// Host clients connect to, put into `users wg-config` output
+    pub endpoint: Option<String>,
+    /// `DNS` of the client config
+    pub dns: Option<String>,
+}
+
+impl Default for WireguardSettings {
+    fn default() -> Self {
+        Self {
+            address_pool: "10.66.0.0/16".to_string(),
+            endpoint: None,
+            dns: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the file (a missing default file is fine) and applies env overrides
+    pub fn load(path: Option<&str>) -> anyhow::Result<Self> {
+        let explicit = path.map(str::to_string).or_else(|| env::var("NECKO_CONFIG").ok());
+        let path = explicit.clone().unwrap_or_else(|| DEFAULT_PATH.to_string());
+
+        let mut settings = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str::<Settings>(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path, e))?,
+            Err(_) if explicit.is_none() => Settings::default(),
+            Err(e) => anyhow::bail!("Cannot read config {}: {}", path, e),
+        };
+
+        settings.apply_env()?;
+        Ok(settings)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        override_with(&mut self.daemon.socket_path, "NECKO_SOCKET_PATH")?;
+        override_with(&mut self.daemon.pid_file, "NECKO_PID_FILE")?;
+        if let Ok(raw) = env::var("NECKO_SOCKET_MODE") {
+            self.daemon.socket_mode = u32::from_str_radix(raw.trim_start_matches("0o"), 8)
+                .map_err(|e| anyhow::anyhow!("Invalid NECKO_SOCKET_MODE `{}`: {}", raw, e))?;
+        }
+        if let Ok(group) = env::var("NECKO_SOCKET_GROUP") {
+            self.daemon.socket_group = Some(group);
+        }
+        override_list(&mut self.daemon.admin_uids, "NECKO_ADMIN_UIDS")?;
+        override_list(&mut self.daemon.admin_gids, "NECKO_ADMIN_GIDS")?;
+        override_list(&mut self.daemon.readonly_uids, "NECKO_READONLY_UIDS")?;
+        override_list(&mut self.daemon.readonly_gids, "NECKO_READONLY_GIDS")?;
+
+        override_with(&mut self.xray.bin, "NECKO_XRAY_BIN")?;
+        override_with(&mut self.xray.config, "NECKO_XRAY_CONFIG")?;
+        override_with(&mut self.xray.pid_file, "NECKO_XRAY_PID_FILE")?;
+        override_with(&mut self.xray.profiles_dir, "NECKO_PROFILES_DIR")?;
+        override_with(&mut self.xray.profile, "NECKO_PROFILE")?;
+        override_with(&mut self.xray.api_port, "XRAY_API_PORT")?;
+        if let Ok(file) = env::var("XRAY_LOG_FILE") {
+            self.xray.log_file = Some(file);
+        }
+        override_with(&mut self.xray.log_stdout, "XRAY_LOG_STDOUT")?;
+
+        if let Ok(url) = env::var("DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        override_with(&mut self.database.max_connections, "NECKO_DB_MAX_CONNECTIONS")?;
+
+        override_with(&mut self.accounting.interval, "NECKO_ACCOUNTING_INTERVAL")?;
+        override_with(&mut self.accounting.sample_retention, "NECKO_ACCOUNTING_SAMPLE_RETENTION")?;
+        override_with(&mut self.online.poll_interval, "NECKO_ONLINE_POLL_INTERVAL")?;
+
+        override_with(&mut self.wireguard.address_pool, "NECKO_WIREGUARD_ADDRESS_POOL")?;
+        if let Ok(endpoint) = env::var("NECKO_WIREGUARD_ENDPOINT") {
+            self.wireguard.endpoint = Some(endpoint);
+        }
+        if let Ok(dns) = env::var("NECKO_WIREGUARD_DNS") {
+            self.wireguard.dns = Some(dns);
+        }
+
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> anyhow::Result<String> {
+        if let Some(url) = &self.database.url {
+            return Ok(url.clone());
+        }
+
+        Ok(format!("postgresql://{}:{}@localhost:5432/{}",
+                   env::var("POSTGRES_USER")
+                       .map_err(|_| anyhow::anyhow!("POSTGRES_USER is not set"))?,
+                   env::var("POSTGRES_PASSWORD")
+                       .map_err(|_| anyhow::anyhow!("POSTGRES_PASSWORD is not set"))?,
+                   env::var("POSTGRES_DB")
+                       .map_err(|_| anyhow::anyhow!("POSTGRES_DB is not set"))?))
+    }
+
+    pub fn profile_path(&self, profile: &str) -> String {
+        format!("{}/{}", self.xray.profiles_dir.trim_end_matches('/'), profile)
+    }
+}
+
+fn override_with<T: FromStr>(value: &mut T, var: &str) -> anyhow::Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(var) {
+        *value = raw
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid {} `{}`: {}", var, raw, e))?;
+    }
+    Ok(())
+}
+
+/// Comma separated list, e.g. `1000,1001`
+fn override_list<T: FromStr>(value: &mut Vec<T>, var: &str) -> anyhow::Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(var) {
+        *value = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid {} `{}`: {}", var, item, e)))
+            .collect::<anyhow::Result<_>>()?;
+    }
+    Ok(())
+}
+
+/// Loads settings once at startup; `path` comes from `--config`
+pub fn init(path: Option<&str>) -> anyhow::Result<()> {
+    let settings = Settings::load(path)?;
+    let _ = SETTINGS.set(settings);
+    Ok(())
+}
+
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings::load(None)
+        .unwrap_or_else(|e| panic!("Failed to load settings: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_test() {
+        let settings: Settings = toml::from_str(
+            include_str!("../necko.example.toml")).unwrap();
+        let defaults = Settings::default();
+
+        assert_eq!(settings.daemon.socket_path, defaults.daemon.socket_path);
+        assert_eq!(settings.daemon.socket_mode, defaults.daemon.socket_mode);
+        assert_eq!(settings.xray.profile, defaults.xray.profile);
+        assert_eq!(settings.xray.api_port, defaults.xray.api_port);
+        assert_eq!(settings.database.max_connections, defaults.database.max_connections);
+        assert_eq!(settings.accounting.interval, defaults.accounting.interval);
+        assert_eq!(settings.accounting.sample_retention, defaults.accounting.sample_retention);
+        assert_eq!(settings.online.poll_interval, defaults.online.poll_interval);
+        assert_eq!(settings.wireguard.address_pool, defaults.wireguard.address_pool);
+    }
+}